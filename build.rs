@@ -0,0 +1,26 @@
+use std::process::Command;
+
+// Embeds the git commit being built as `RECAST_GIT_SHA`, for `/version`.
+// An explicitly set `RECAST_GIT_SHA` wins, for builds without a `.git`
+// directory (e.g. Docker).
+fn main() {
+    println!("cargo:rerun-if-env-changed=RECAST_GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let sha = std::env::var("RECAST_GIT_SHA")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .or_else(|| {
+            let out = Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()?;
+            out.status
+                .success()
+                .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        });
+    if let Some(sha) = sha {
+        println!("cargo:rustc-env=RECAST_GIT_SHA={}", sha);
+    }
+}
@@ -0,0 +1,330 @@
+use std::{convert::Infallible, future::Future, time::Instant};
+
+use http::{HeaderMap, HeaderValue, Method, StatusCode};
+use log::{info, warn};
+use warp::{
+    cors::CorsForbidden,
+    path::FullPath,
+    reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+        PayloadTooLarge, UnsupportedMediaType,
+    },
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use crate::{fetch::redact_query, rss::handle_error};
+
+/// The log target of access log lines, so their level can be set apart from
+/// other logs, e.g. with `RUST_LOG=recast::access=info`.
+const TARGET: &str = "recast::access";
+
+/// The header a request's ID is taken from, if the client or a proxy in
+/// front of recast set it, and echoed in.
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The longest `X-Request-Id` taken from a request; longer ones are
+/// replaced, as are ones with characters that do not belong in a log line.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    /// The ID of the request being handled, which `Traced` prefixes log
+    /// lines with.
+    static REQUEST_ID: String;
+}
+
+/// How access log lines are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// `[<request ID>] GET /rss?url=...&delay=1d 200 12.3ms`
+    Text,
+    /// One JSON object per request.
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "unknown format '{}', valid formats are text and json",
+                s
+            )),
+        }
+    }
+}
+
+/// The ID of a request: its `X-Request-Id`, or a new random UUID if it has
+/// none or one that is not short, printable ASCII.
+pub(crate) fn request_id() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::header::headers_cloned().map(|headers: HeaderMap| {
+        headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LEN
+                    && id.bytes().all(|b| b.is_ascii_graphic())
+            })
+            .map_or_else(new_request_id, str::to_string)
+    })
+}
+
+/// A random version 4 UUID.
+fn new_request_id() -> String {
+    let bits = rand::random::<u128>() & !(0xf << 76) & !(0x3 << 62) | (0x4 << 76) | (0x2 << 62);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Runs `handling` as the request with ID `id`: the lines it logs are
+/// prefixed with the ID, and its response, including the errors
+/// `handle_error` answers, has it in `X-Request-Id`.
+pub(crate) async fn traced<R: Reply>(
+    id: String,
+    handling: impl Future<Output = Result<R, Rejection>>,
+) -> Result<Response, Rejection> {
+    let res = REQUEST_ID
+        .scope(id.clone(), async move {
+            match handling.await {
+                Ok(reply) => Ok(reply.into_response()),
+                Err(err) => handle_error(err).await.map(Reply::into_response),
+            }
+        })
+        .await?;
+    Ok(with_request_id(res, &id))
+}
+
+/// `res` with `id` in its `X-Request-Id`, unless it already has one.
+fn with_request_id(mut res: Response, id: &str) -> Response {
+    if !res.headers().contains_key(REQUEST_ID_HEADER) {
+        if let Ok(id) = HeaderValue::from_str(id) {
+            res.headers_mut().insert(REQUEST_ID_HEADER, id);
+        }
+    }
+    res
+}
+
+/// A logger prefixing the lines logged while handling a request with the
+/// request's ID, so they can be told apart from those of other requests.
+pub(crate) struct Traced<L>(pub(crate) L);
+
+impl<L: log::Log> log::Log for Traced<L> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        let logged = REQUEST_ID.try_with(|id| {
+            self.0.log(
+                &log::Record::builder()
+                    .args(format_args!("[{}] {}", id, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            )
+        });
+        if logged.is_err() {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Wraps `routes` to log every request's ID, method, path with query, status
+/// and duration. Requests no route accepts are answered as warp would, so
+/// they are logged too, and every response has the request's ID in
+/// `X-Request-Id`.
+pub(crate) fn logged<F, R>(
+    routes: F,
+    format: Format,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    warp::any()
+        .map(Instant::now)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(request_id())
+        .and(
+            routes
+                .map(Reply::into_response)
+                .recover(handle_rejection)
+                .unify(),
+        )
+        .map(
+            move |start: Instant,
+                  method: Method,
+                  path: FullPath,
+                  query: String,
+                  id: String,
+                  res: Response| {
+                let path = logged_path(&path, &query);
+                // A route that generated its own ID for a request without
+                // one has already set it.
+                let res = with_request_id(res, &id);
+                let id = res.headers()[REQUEST_ID_HEADER].to_str().unwrap_or(&id);
+                log_request(format, id, &method, &path, res.status(), start);
+                res
+            },
+        )
+}
+
+/// The path and query of a request as logged, without credentials.
+fn logged_path(path: &FullPath, query: &str) -> String {
+    match query {
+        "" => path.as_str().to_string(),
+        query => format!("{}?{}", path.as_str(), redact_query(query)),
+    }
+}
+
+fn log_request(
+    format: Format,
+    id: &str,
+    method: &Method,
+    path: &str,
+    status: StatusCode,
+    start: Instant,
+) {
+    let elapsed = start.elapsed();
+    match format {
+        Format::Text => info!(
+            target: TARGET,
+            "[{}] {} {} {} {:.1}ms",
+            id,
+            method,
+            path,
+            status.as_u16(),
+            elapsed.as_secs_f64() * 1000.0
+        ),
+        Format::Json => info!(
+            target: TARGET,
+            "{}",
+            serde_json::json!({
+                "request_id": id,
+                "method": method.as_str(),
+                "path": path,
+                "status": status.as_u16(),
+                "duration_ms": elapsed.as_secs_f64() * 1000.0,
+            })
+        ),
+    }
+}
+
+/// Answers a rejection no route recovered from with the status warp would
+/// have given it.
+async fn handle_rejection(err: Rejection) -> Result<Response, Infallible> {
+    let status = if err.find::<CorsForbidden>().is_some() {
+        StatusCode::FORBIDDEN
+    } else if err.find::<PayloadTooLarge>().is_some() {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else if err.find::<UnsupportedMediaType>().is_some() {
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    } else if err.find::<LengthRequired>().is_some() {
+        StatusCode::LENGTH_REQUIRED
+    } else if err.find::<MissingHeader>().is_some()
+        || err.find::<InvalidHeader>().is_some()
+        || err.find::<InvalidQuery>().is_some()
+    {
+        StatusCode::BAD_REQUEST
+    } else if err.find::<MethodNotAllowed>().is_some() {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else if err.is_not_found() {
+        StatusCode::NOT_FOUND
+    } else {
+        warn!("unhandled rejection: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let message = status.canonical_reason().unwrap_or_default();
+    Ok(warp::reply::with_status(message, status).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn logged_keeps_statuses_of_rejections() {
+        let routes = logged(
+            warp::get().and(warp::path!("hello")).map(|| "hello"),
+            Format::Text,
+        );
+        for (method, path, status) in [
+            ("GET", "/hello?name=x", StatusCode::OK),
+            ("POST", "/hello", StatusCode::METHOD_NOT_ALLOWED),
+            ("GET", "/missing", StatusCode::NOT_FOUND),
+        ] {
+            let res = warp::test::request()
+                .method(method)
+                .path(path)
+                .reply(&routes)
+                .await;
+            assert_eq!(res.status(), status, "{} {}", method, path);
+        }
+    }
+
+    #[tokio::test]
+    async fn logged_path_leaves_out_credentials() {
+        let route = warp::path::full()
+            .and(warp::query::raw())
+            .map(|path: FullPath, query: String| logged_path(&path, &query));
+        let res = warp::test::request()
+            .path("/rss?url=https%3A%2F%2Fuser%3As3cret%40example.com%2Ffeed&auth_pass=hunter2&delay=1d")
+            .reply(&route)
+            .await;
+        assert_eq!(
+            res.body(),
+            "/rss?url=https%3A%2F%2Fexample.com%2Ffeed&delay=1d"
+        );
+    }
+
+    #[tokio::test]
+    async fn traced_echoes_or_generates_request_ids() {
+        let routes = logged(
+            warp::path!("fails").and(request_id()).and_then(|id| {
+                traced(id, async {
+                    Err::<&str, _>(warp::reject::custom(crate::Error::QueryParse(
+                        "bad".to_string(),
+                    )))
+                })
+            }),
+            Format::Text,
+        );
+        let res = warp::test::request()
+            .path("/fails")
+            .header(REQUEST_ID_HEADER, "abc-123")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], "abc-123");
+
+        for (path, given) in [("/fails", "has spaces"), ("/missing", "")] {
+            let res = warp::test::request()
+                .path(path)
+                .header(REQUEST_ID_HEADER, given)
+                .reply(&routes)
+                .await;
+            let id = res.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+            assert_eq!(id.len(), 36, "{}", path);
+            assert_eq!(id.as_bytes()[14], b'4', "{}", path);
+        }
+    }
+}
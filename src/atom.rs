@@ -0,0 +1,351 @@
+use std::collections::BTreeMap;
+
+use atom_syndication::{extension::Extension, Entry, Feed, Link, Person, Source, Text, TextType};
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use rss::{Channel, Guid, Item};
+
+use url::Url;
+
+use crate::transform::{
+    absolute_url, absolutize_html, annotate, early_note, html_to_text, humanize, item_date,
+    original_pub_date, truncate_words, Annotate, Keywords, Query, NAMESPACE, NAMESPACE_PREFIX,
+    ORIGINAL_PUB_DATE,
+};
+
+pub(crate) fn update_title(feed: &mut Feed, delay: Duration) {
+    let ht = humanize(delay);
+    feed.title.value = format!("{} (Rerun after {})", feed.title.value, ht);
+}
+
+/// Points the feed's `self` link at recast, adding one if the feed has none.
+pub(crate) fn update_link(feed: &mut Feed, link: String) {
+    match feed.links.iter_mut().find(|l| l.rel == "self") {
+        Some(l) => l.href = link,
+        None => feed.links.push(Link {
+            href: link,
+            rel: "self".to_string(),
+            ..Default::default()
+        }),
+    }
+}
+
+pub(crate) fn matches(entry: &Entry, keywords: &Keywords) -> bool {
+    let summary = entry
+        .summary
+        .as_ref()
+        .map(|s| s.value.as_str())
+        .or_else(|| entry.content.as_ref().and_then(|c| c.value.as_deref()));
+    keywords.matches(&entry.title.value, summary.unwrap_or_default())
+}
+
+/// Shifts an entry's `published` and `updated` timestamps by `delay`. The
+/// entry is dropped when the shifted date, taken from `published` or else
+/// `updated`, is still in the future, unless `clamp` moves it to `now`.
+pub(crate) fn postdate_entry(
+    entry: &mut Entry,
+    query: &Query,
+    now: DateTime<Utc>,
+) -> Option<Entry> {
+    let orig_date = entry.published.unwrap_or(entry.updated);
+    let delay = query.shifted_date(orig_date, &entry.id, None, now)? - orig_date;
+
+    if let Some(published) = entry.published {
+        entry.published = Some(published.checked_add_signed(delay)?);
+    }
+    entry.updated = entry.updated.checked_add_signed(delay)?;
+    shorten_summary(entry, query);
+
+    match query.annotate {
+        Annotate::Element => set_original_date(entry, orig_date),
+        Annotate::Description => {
+            if let Some(summary) = entry.summary.as_mut() {
+                summary.value = annotate(query, &summary.value, orig_date);
+            }
+        }
+        Annotate::Off => {}
+    }
+
+    Some(entry.to_owned())
+}
+
+/// Replaces the entry's summary with its text if `strip_html` is set, and
+/// marks it as plain text, then cuts it to `desc_words` words.
+fn shorten_summary(entry: &mut Entry, query: &Query) {
+    let Some(summary) = entry.summary.as_mut() else {
+        return;
+    };
+    if query.strip_html {
+        summary.value = html_to_text(&summary.value);
+        summary.r#type = TextType::Text;
+    }
+    if let Some(text) = query
+        .desc_words
+        .and_then(|words| truncate_words(&summary.value, words))
+    {
+        summary.value = text;
+    }
+}
+
+/// Notes `date` in the entry's `recast:originalPubDate`.
+fn set_original_date(entry: &mut Entry, date: DateTime<FixedOffset>) {
+    let ext = Extension {
+        name: format!("{}:{}", NAMESPACE_PREFIX, ORIGINAL_PUB_DATE),
+        value: Some(date.to_rfc3339()),
+        ..Default::default()
+    };
+    entry
+        .extensions
+        .entry(NAMESPACE_PREFIX.to_string())
+        .or_default()
+        .insert(ORIGINAL_PUB_DATE.to_string(), vec![ext]);
+}
+
+/// The original publication date in the entry's `recast:originalPubDate`.
+fn original_date(entry: &Entry) -> Option<DateTime<FixedOffset>> {
+    let date = entry
+        .extensions
+        .get(NAMESPACE_PREFIX)?
+        .get(ORIGINAL_PUB_DATE)?
+        .first()?
+        .value()?;
+    DateTime::parse_from_rfc3339(date).ok()
+}
+
+/// Shifts a not yet due entry by the delay, like `postdate_entry`, and
+/// notes in its summary that it is shown early to make up `min_items`.
+pub(crate) fn postdate_early(entry: &mut Entry, query: &Query) {
+    let orig_date = entry.published.unwrap_or(entry.updated);
+    let delay = query
+        .due_date(orig_date, &entry.id, None)
+        .map_or(query.delay, |due| due - orig_date);
+    entry.published = entry
+        .published
+        .map(|p| p.checked_add_signed(delay).unwrap_or(p));
+    entry.updated = entry
+        .updated
+        .checked_add_signed(delay)
+        .unwrap_or(entry.updated);
+    let due = entry.published.unwrap_or(entry.updated);
+    shorten_summary(entry, query);
+    let summary = entry.summary.get_or_insert_with(Text::default);
+    summary.value = early_note(query, &summary.value, due);
+}
+
+/// Resolves an entry's relative links, and relative `src` and `href`
+/// attributes in its summary and content, against `base`.
+pub(crate) fn absolutize_entry(entry: &mut Entry, base: &Url) {
+    for link in &mut entry.links {
+        link.href = absolute_url(&link.href, base);
+    }
+    if let Some(summary) = entry.summary.as_mut() {
+        summary.value = absolutize_html(&summary.value, base);
+    }
+    if let Some(value) = entry.content.as_mut().and_then(|c| c.value.as_mut()) {
+        *value = absolutize_html(value, base);
+    }
+}
+
+/// Converts an Atom feed into an RSS channel, keeping the fields recast knows
+/// how to carry over.
+pub(crate) fn to_channel(feed: &Feed) -> Channel {
+    let mut channel = Channel::default();
+    channel.set_title(feed.title.value.clone());
+    channel.set_link(alternate_link(&feed.links).unwrap_or_default());
+    channel.set_description(
+        feed.subtitle
+            .as_ref()
+            .map(|s| s.value.clone())
+            .unwrap_or_default(),
+    );
+    channel.set_last_build_date(feed.updated.to_rfc2822());
+    channel.set_items(
+        feed.entries
+            .iter()
+            .map(|e| {
+                let mut item = Item::default();
+                item.set_title(e.title.value.clone());
+                item.set_link(alternate_link(&e.links));
+                item.set_enclosure(e.links.iter().find(|l| l.rel == "enclosure").map(|l| {
+                    rss::Enclosure {
+                        url: l.href.clone(),
+                        length: l.length.clone().unwrap_or_else(|| "0".to_string()),
+                        mime_type: l.mime_type.clone().unwrap_or_default(),
+                    }
+                }));
+                item.set_guid(Guid {
+                    value: e.id.clone(),
+                    permalink: false,
+                });
+                item.set_pub_date(e.published.unwrap_or(e.updated).to_rfc2822());
+                item.set_description(
+                    e.summary
+                        .as_ref()
+                        .map(|s| s.value.clone())
+                        .or_else(|| e.content.as_ref().and_then(|c| c.value.clone())),
+                );
+                item.set_source(e.source.as_ref().and_then(|s| {
+                    Some(rss::Source {
+                        url: alternate_link(&s.links)?,
+                        title: Some(s.title.value.clone()).filter(|t| !t.is_empty()),
+                    })
+                }));
+                if let Some(date) = original_date(e) {
+                    let ext = rss::extension::Extension {
+                        name: format!("{}:{}", NAMESPACE_PREFIX, ORIGINAL_PUB_DATE),
+                        value: Some(date.to_rfc2822()),
+                        ..Default::default()
+                    };
+                    item.extensions
+                        .entry(NAMESPACE_PREFIX.to_string())
+                        .or_default()
+                        .insert(ORIGINAL_PUB_DATE.to_string(), vec![ext]);
+                }
+                item
+            })
+            .collect::<Vec<_>>(),
+    );
+    with_namespace(&mut channel.namespaces, &feed.namespaces);
+    channel
+}
+
+/// Declares recast's namespace in `to` if it is declared in `from`, so the
+/// `recast:originalPubDate` of converted items stays declared.
+fn with_namespace(to: &mut BTreeMap<String, String>, from: &BTreeMap<String, String>) {
+    if from.contains_key(NAMESPACE_PREFIX) {
+        to.insert(NAMESPACE_PREFIX.to_string(), NAMESPACE.to_string());
+    }
+}
+
+/// Converts an RSS channel into an Atom feed. Items without a parseable
+/// `pubDate` are dated `now`, since Atom requires `updated`.
+pub(crate) fn from_channel(channel: &Channel, now: DateTime<Utc>) -> Feed {
+    let mut feed = Feed {
+        title: channel.title().into(),
+        id: channel.link().to_string(),
+        updated: channel
+            .last_build_date()
+            .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+            .unwrap_or_else(|| now.into()),
+        links: vec![Link {
+            href: channel.link().to_string(),
+            ..Default::default()
+        }],
+        subtitle: Some(channel.description().into()),
+        entries: channel
+            .items()
+            .iter()
+            .map(|i| {
+                let mut entry = Entry {
+                    title: i.title().unwrap_or_default().into(),
+                    id: i
+                        .guid()
+                        .map(|g| g.value().to_string())
+                        .or_else(|| i.link().map(str::to_string))
+                        .unwrap_or_default(),
+                    updated: item_date(i).map(|(d, _)| d).unwrap_or_else(|| now.into()),
+                    published: item_date(i).map(|(d, _)| d),
+                    links: i
+                        .link()
+                        .map(|l| Link {
+                            href: l.to_string(),
+                            ..Default::default()
+                        })
+                        .into_iter()
+                        .chain(i.enclosure().map(|e| Link {
+                            href: e.url().to_string(),
+                            rel: "enclosure".to_string(),
+                            mime_type: Some(e.mime_type().to_string()).filter(|t| !t.is_empty()),
+                            length: Some(e.length().to_string()),
+                            ..Default::default()
+                        }))
+                        .collect(),
+                    summary: i.description().map(Text::html),
+                    ..Default::default()
+                };
+                entry.source = i.source().map(|s| Source {
+                    title: s.title().unwrap_or_default().into(),
+                    id: s.url().to_string(),
+                    updated: entry.updated,
+                    links: vec![Link {
+                        href: s.url().to_string(),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                });
+                if let Some(date) =
+                    original_pub_date(i).and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+                {
+                    set_original_date(&mut entry, date);
+                }
+                entry
+            })
+            .collect(),
+        ..Default::default()
+    };
+    with_namespace(&mut feed.namespaces, &channel.namespaces);
+    feed
+}
+
+/// `feed` as XML. atom_syndication writes the text of a few elements, such as
+/// `id`, as it is rather than escaped, so those are escaped first; a feed
+/// whose `id` is a URL with a query would not be well-formed otherwise.
+pub(crate) fn to_xml(mut feed: Feed) -> String {
+    fn escape(s: &mut String) {
+        if s.contains(['&', '<', '>']) {
+            *s = s
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+        }
+    }
+    fn escape_people(people: &mut [Person]) {
+        for person in people {
+            escape(&mut person.name);
+            person
+                .email
+                .iter_mut()
+                .chain(person.uri.iter_mut())
+                .for_each(escape);
+        }
+    }
+
+    escape(&mut feed.id);
+    feed.icon
+        .iter_mut()
+        .chain(feed.logo.iter_mut())
+        .for_each(escape);
+    if let Some(generator) = feed.generator.as_mut() {
+        escape(&mut generator.value);
+    }
+    escape_people(&mut feed.authors);
+    escape_people(&mut feed.contributors);
+    for entry in &mut feed.entries {
+        escape(&mut entry.id);
+        escape_people(&mut entry.authors);
+        escape_people(&mut entry.contributors);
+        if let Some(source) = entry.source.as_mut() {
+            escape(&mut source.id);
+            source
+                .icon
+                .iter_mut()
+                .chain(source.logo.iter_mut())
+                .for_each(escape);
+            if let Some(generator) = source.generator.as_mut() {
+                escape(&mut generator.value);
+            }
+            escape_people(&mut source.authors);
+            escape_people(&mut source.contributors);
+        }
+    }
+    feed.to_string()
+}
+
+/// The `rel="alternate"` link, or else the first one that is not an
+/// enclosure.
+fn alternate_link(links: &[Link]) -> Option<String> {
+    links
+        .iter()
+        .find(|l| l.rel == "alternate")
+        .or_else(|| links.iter().find(|l| l.rel != "enclosure"))
+        .map(|l| l.href.clone())
+}
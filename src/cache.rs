@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Duration as ChronoDuration, DurationRound, Utc};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use http::{header, HeaderMap};
+use tokio::sync::RwLock;
+
+use crate::transform::{Error, Feed};
+
+/// The most feeds cached at once. Past this, the one fetched longest ago is
+/// evicted to make room.
+const MAX_ENTRIES: usize = 1_000;
+
+/// How many times its TTL an expired feed with validators is kept for, to
+/// be revalidated rather than fetched again.
+const STALE_TTLS: u32 = 10;
+
+/// Past this many recast feeds, when each was last modified is forgotten,
+/// and they count as modified when next served.
+const MAX_SERVED: usize = 10_000;
+
+/// The items ETag a recast feed was last served with, and when it was first
+/// served with it.
+type Served = (String, DateTime<Utc>);
+
+/// The outcome of loading a feed, shared by every request waiting for it.
+pub(crate) type Loaded = Result<(HeaderMap, Arc<Feed>), Error>;
+
+/// A fetched feed, with the headers upstream returned it with.
+#[derive(Clone)]
+pub(crate) struct Entry {
+    pub(crate) headers: HeaderMap,
+    /// The parsed feed, shared by every request it is served to. Requests
+    /// transform a copy from `Entry::feed`.
+    feed: Arc<Feed>,
+    fetched_at: Instant,
+}
+
+impl Entry {
+    /// A copy of the feed for a request to transform.
+    pub(crate) fn feed(&self) -> Feed {
+        Feed::clone(&self.feed)
+    }
+
+    /// The headers and the shared feed.
+    pub(crate) fn into_parts(self) -> (HeaderMap, Arc<Feed>) {
+        (self.headers, self.feed)
+    }
+
+    /// The headers that make a refetch of this feed conditional, so upstream
+    /// can answer `304 Not Modified` instead of sending it again.
+    pub(crate) fn validators(&self) -> HeaderMap {
+        let mut validators = HeaderMap::new();
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            validators.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(modified) = self.headers.get(header::LAST_MODIFIED) {
+            validators.insert(header::IF_MODIFIED_SINCE, modified.clone());
+        }
+        validators
+    }
+
+    fn is_fresh(&self, ttl: Duration) -> bool {
+        self.fetched_at.elapsed() < ttl
+    }
+
+    /// Whether the entry is still worth keeping: it is fresh, or it expired
+    /// less than `STALE_TTLS` TTLs ago and can be revalidated.
+    fn is_worth_keeping(&self, ttl: Duration) -> bool {
+        self.is_fresh(ttl) || self.has_validators() && self.is_fresh(ttl * STALE_TTLS)
+    }
+
+    fn has_validators(&self) -> bool {
+        self.headers.contains_key(header::ETAG) || self.headers.contains_key(header::LAST_MODIFIED)
+    }
+}
+
+/// An in-memory cache of fetched feeds, keyed by URL. Cloning it is cheap and
+/// yields a handle to the same cache.
+#[derive(Clone)]
+pub(crate) struct FeedCache {
+    ttl: Duration,
+    entries: Arc<RwLock<HashMap<String, Entry>>>,
+    /// The loads of feeds under way, by URL, for requests for the same feed
+    /// to wait for rather than start their own.
+    in_flight: Arc<Mutex<HashMap<String, Shared<BoxFuture<'static, Loaded>>>>>,
+    /// Recast feeds by URL, to date their `Last-Modified`.
+    served: Arc<Mutex<HashMap<String, Served>>>,
+}
+
+impl FeedCache {
+    /// Creates a cache whose entries expire after `ttl`. A zero `ttl`
+    /// disables caching.
+    pub(crate) fn new(ttl: Duration) -> FeedCache {
+        FeedCache {
+            ttl,
+            entries: Arc::default(),
+            in_flight: Arc::default(),
+            served: Arc::default(),
+        }
+    }
+
+    /// Runs `load`, which loads the feed at `url`, unless a load of it is
+    /// already under way, in which case waits for that one. This keeps a
+    /// burst of requests for a feed that is not cached from fetching it once
+    /// each, even with caching disabled. The load runs on its own task, so it
+    /// finishes for the other requests even if the one that started it gives
+    /// up.
+    pub(crate) async fn single_flight(
+        &self,
+        url: &str,
+        load: impl Future<Output = Loaded> + Send + 'static,
+    ) -> Loaded {
+        let flight = self
+            .in_flight
+            .lock()
+            .expect("in-flight loads poisoned")
+            .entry(url.to_string())
+            .or_insert_with(|| {
+                let in_flight = self.in_flight.clone();
+                let url = url.to_string();
+                let task = tokio::spawn(async move {
+                    let loaded = load.await;
+                    // The feed is cached by now, so later requests get it
+                    // from there.
+                    in_flight
+                        .lock()
+                        .expect("in-flight loads poisoned")
+                        .remove(&url);
+                    loaded
+                });
+                async move { task.await.expect("loading a feed panicked") }
+                    .boxed()
+                    .shared()
+            })
+            .clone();
+        flight.await
+    }
+
+    /// When the recast feed at `link` was first served with the items `etag`
+    /// stands for, to the second: `now`, unless it was last served with them
+    /// too. A feed changing twice within a second is dated a second after
+    /// the first change, so each version has its own date.
+    pub(crate) fn last_modified(
+        &self,
+        link: &str,
+        etag: &str,
+        now: DateTime<Utc>,
+    ) -> DateTime<Utc> {
+        let now = now
+            .duration_trunc(ChronoDuration::seconds(1))
+            .unwrap_or(now);
+        let mut served = self.served.lock().expect("served feeds poisoned");
+        if served.len() >= MAX_SERVED && !served.contains_key(link) {
+            served.clear();
+        }
+        let (served_etag, modified) = served
+            .entry(link.to_string())
+            .or_insert_with(|| (etag.to_string(), now));
+        if served_etag != etag {
+            *modified = now.max(*modified + ChronoDuration::seconds(1));
+            *served_etag = etag.to_string();
+        }
+        *modified
+    }
+
+    /// Returns the feed cached for `url`, unless it has expired.
+    pub(crate) async fn get(&self, url: &str) -> Option<Entry> {
+        self.get_stale(url).await.filter(|e| e.is_fresh(self.ttl))
+    }
+
+    /// Returns the feed cached for `url`, even if it has expired. Expired
+    /// feeds are only kept for a while, and only if they can be revalidated
+    /// with a conditional request.
+    pub(crate) async fn get_stale(&self, url: &str) -> Option<Entry> {
+        self.entries.read().await.get(url).cloned()
+    }
+
+    /// Marks the cached `entry` for `url` as fetched just now, after upstream
+    /// confirmed it is unchanged.
+    pub(crate) async fn refresh(&self, url: &str, entry: Entry) {
+        self.insert(url, entry.headers, entry.feed).await;
+    }
+
+    /// Caches the feed fetched from `url`, evicting any entries no longer
+    /// worth keeping, and the oldest one if the cache is full.
+    pub(crate) async fn insert(&self, url: &str, headers: HeaderMap, feed: Arc<Feed>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.write().await;
+        entries.retain(|_, e| e.is_worth_keeping(self.ttl));
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(url) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, e)| e.fetched_at)
+                .map(|(url, _)| url.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            url.to_string(),
+            Entry {
+                headers,
+                feed,
+                fetched_at: Instant::now(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validators() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, "\"v1\"".parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn insert_caps_the_number_of_entries() {
+        let cache = FeedCache::new(Duration::from_secs(60));
+        let feed = Arc::new(Feed::Rss(Box::default()));
+        for n in 0..MAX_ENTRIES + 10 {
+            let url = format!("http://example.com/{}", n);
+            cache.insert(&url, validators(), feed.clone()).await;
+        }
+        assert_eq!(cache.entries.read().await.len(), MAX_ENTRIES);
+        // The oldest are evicted first.
+        assert!(cache.get("http://example.com/0").await.is_none());
+        let newest = format!("http://example.com/{}", MAX_ENTRIES + 9);
+        assert!(cache.get(&newest).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn insert_drops_long_stale_entries() {
+        let ttl = Duration::from_millis(10);
+        let cache = FeedCache::new(ttl);
+        let feed = Arc::new(Feed::Rss(Box::default()));
+        cache
+            .insert("http://example.com/a", validators(), feed.clone())
+            .await;
+        cache
+            .insert("http://example.com/b", HeaderMap::new(), feed.clone())
+            .await;
+        tokio::time::sleep(ttl * 2).await;
+        cache
+            .insert("http://example.com/c", HeaderMap::new(), feed.clone())
+            .await;
+        // Expired, but it can still be revalidated.
+        assert!(cache.get_stale("http://example.com/a").await.is_some());
+        assert!(cache.get_stale("http://example.com/b").await.is_none());
+
+        tokio::time::sleep(ttl * STALE_TTLS).await;
+        cache
+            .insert("http://example.com/c", HeaderMap::new(), feed)
+            .await;
+        assert!(cache.get_stale("http://example.com/a").await.is_none());
+    }
+}
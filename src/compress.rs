@@ -0,0 +1,151 @@
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+use http::{header, HeaderValue, StatusCode};
+use log::warn;
+use warp::{hyper::Body, reply::Response, Filter, Rejection, Reply};
+
+/// Bodies smaller than this are sent as they are, since gzip would barely
+/// shrink them.
+const MIN_SIZE: usize = 1024;
+
+/// Wraps `routes` to gzip the bodies of their responses for clients whose
+/// `Accept-Encoding` allows it. Small bodies, and responses that are already
+/// encoded, are left alone.
+pub(crate) fn gzipped<F, R>(
+    routes: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    warp::header::optional::<String>(header::ACCEPT_ENCODING.as_str())
+        .and(routes)
+        .and_then(|accept: Option<String>, reply: R| async move {
+            Ok::<_, Rejection>(compress(accept.as_deref(), reply.into_response()).await)
+        })
+}
+
+async fn compress(accept_encoding: Option<&str>, mut res: Response) -> Response {
+    if res.headers().contains_key(header::CONTENT_ENCODING)
+        || res.status() == StatusCode::NOT_MODIFIED
+    {
+        return res;
+    }
+    res.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    if !accept_encoding.is_some_and(accepts_gzip) {
+        return res;
+    }
+
+    let body = match warp::hyper::body::to_bytes(std::mem::take(res.body_mut())).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("failed to read response body: {}", e);
+            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return res;
+        }
+    };
+    if body.len() < MIN_SIZE {
+        *res.body_mut() = Body::from(body);
+        return res;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let gzipped = encoder
+        .write_all(&body)
+        .and_then(|_| encoder.finish())
+        .expect("compressing into memory cannot fail");
+    res.headers_mut().remove(header::CONTENT_LENGTH);
+    res.headers_mut()
+        .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    *res.body_mut() = Body::from(gzipped);
+    res
+}
+
+/// Whether an `Accept-Encoding` header allows gzip: it lists `gzip`, or `*`
+/// without also listing `gzip`, with a non-zero quality.
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    let codings: Vec<(&str, bool)> = accept_encoding
+        .split(',')
+        .map(|c| {
+            let mut params = c.split(';').map(str::trim);
+            let coding = params.next().unwrap_or_default();
+            let refused = params.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (coding, !refused)
+        })
+        .collect();
+    let find = |name: &str| {
+        codings
+            .iter()
+            .find(|(c, _)| c.eq_ignore_ascii_case(name))
+            .map(|(_, accepted)| *accepted)
+    };
+    find("gzip").or_else(|| find("*")).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn accepts_gzip_reads_qualities() {
+        for (header, accepted) in [
+            ("gzip", true),
+            ("deflate, GZIP;q=0.5", true),
+            ("br", false),
+            ("*", true),
+            ("gzip;q=0, *", false),
+            ("*;q=0", false),
+            ("identity", false),
+        ] {
+            assert_eq!(accepts_gzip(header), accepted, "{}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn gzipped_compresses_large_bodies() {
+        let large = "<item></item>".repeat(200);
+        let route = gzipped(warp::path!("large").map(move || large.clone()))
+            .or(gzipped(warp::path!("small").map(|| "<rss/>")));
+
+        let res = warp::test::request()
+            .path("/large")
+            .header("accept-encoding", "gzip")
+            .reply(&route)
+            .await;
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(res.headers()[header::VARY], "accept-encoding");
+        let mut body = String::new();
+        GzDecoder::new(&res.body()[..])
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "<item></item>".repeat(200));
+
+        for (path, accept) in [
+            ("/large", None),
+            ("/large", Some("br")),
+            ("/small", Some("gzip")),
+        ] {
+            let mut req = warp::test::request().path(path);
+            if let Some(accept) = accept {
+                req = req.header("accept-encoding", accept);
+            }
+            let res = req.reply(&route).await;
+            assert!(
+                !res.headers().contains_key(header::CONTENT_ENCODING),
+                "{} {:?}",
+                path,
+                accept
+            );
+            assert!(res.body().starts_with(b"<"), "{} {:?}", path, accept);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::Duration;
+use log::warn;
+use tokio::sync::Semaphore;
+use url::Url;
+
+use crate::{
+    access,
+    transform::{parse_delay, split_list, Limits},
+};
+
+/// Everything recast is configured with, read from the environment once at
+/// startup.
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    /// The address to listen on.
+    pub(crate) bind_addr: SocketAddr,
+    /// How many connections may wait to be accepted before new ones are
+    /// refused. Like `reuse_addr`, it only applies to plain HTTP: warp binds
+    /// TLS listeners itself, with its defaults.
+    pub(crate) listen_backlog: u32,
+    /// Whether the listener sets `SO_REUSEADDR`, so a restarted server can
+    /// bind while the old one's connections linger in `TIME_WAIT`.
+    pub(crate) reuse_addr: bool,
+    /// The origins allowed to fetch feeds from browser scripts. CORS is
+    /// disabled when this is empty.
+    pub(crate) cors_origins: Vec<String>,
+    pub(crate) access_log_format: access::Format,
+    /// The PEM certificate chain and private key to serve HTTPS with. Both
+    /// must be set, or neither for plain HTTP; see `Config::tls`.
+    pub(crate) tls_cert: Option<PathBuf>,
+    pub(crate) tls_key: Option<PathBuf>,
+    /// The limits handed to every request.
+    pub(crate) limits: Limits,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            listen_backlog: 1024,
+            reuse_addr: true,
+            cors_origins: Vec::new(),
+            access_log_format: access::Format::Text,
+            tls_cert: None,
+            tls_key: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Config {
+        Config::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Reads the configuration from the variables `var` looks up by name.
+    /// Unset variables take their default; invalid ones are logged and take
+    /// their default too.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Config {
+        let vars = Vars(var);
+        let default = Config::default();
+        let limits = default.limits;
+        Config {
+            bind_addr: vars.parse_or("RECAST_BIND_ADDR", default.bind_addr),
+            listen_backlog: vars
+                .parse("RECAST_LISTEN_BACKLOG")
+                .filter(|&b| b > 0)
+                .unwrap_or(default.listen_backlog),
+            reuse_addr: vars.parse_or("RECAST_REUSE_ADDR", default.reuse_addr),
+            cors_origins: cors_origins(vars.get("RECAST_CORS_ORIGINS").as_deref()),
+            access_log_format: vars.parse_or("RECAST_ACCESS_LOG_FORMAT", default.access_log_format),
+            tls_cert: vars.get("RECAST_TLS_CERT").map(PathBuf::from),
+            tls_key: vars.get("RECAST_TLS_KEY").map(PathBuf::from),
+            limits: Limits {
+                min_delay: vars
+                    .parse("RECAST_MIN_DELAY_MINUTES")
+                    .map_or(limits.min_delay, Duration::minutes),
+                max_delay: vars
+                    .parse("RECAST_MAX_DELAY_HOURS")
+                    .map_or(limits.max_delay, Duration::hours),
+                default_delay: vars.get("RECAST_DEFAULT_DELAY").and_then(|d| {
+                    parse_delay(&d)
+                        .map_err(|e| {
+                            warn!(
+                                "invalid RECAST_DEFAULT_DELAY {}: {}, using the default",
+                                d, e
+                            )
+                        })
+                        .ok()
+                }),
+                fetch_timeout: vars
+                    .parse("RECAST_FETCH_TIMEOUT_SECS")
+                    .map_or(limits.fetch_timeout, std::time::Duration::from_secs),
+                fetch_attempts: vars
+                    .parse("RECAST_FETCH_ATTEMPTS")
+                    .filter(|&a| a > 0)
+                    .unwrap_or(limits.fetch_attempts),
+                max_feed_bytes: vars.parse_or("RECAST_MAX_FEED_BYTES", limits.max_feed_bytes),
+                max_items: vars
+                    .parse("RECAST_MAX_ITEMS")
+                    .filter(|&n| n > 0)
+                    .unwrap_or(limits.max_items),
+                cache_ttl: vars
+                    .parse("RECAST_CACHE_TTL_SECS")
+                    .map_or(limits.cache_ttl, std::time::Duration::from_secs),
+                cache_max_age: vars
+                    .parse("RECAST_CACHE_MAX_AGE_SECS")
+                    .map_or(limits.cache_max_age, std::time::Duration::from_secs),
+                allowed_cidrs: split_list(vars.get("RECAST_ALLOWED_CIDRS").as_deref())
+                    .iter()
+                    .filter_map(|c| {
+                        c.parse()
+                            .map_err(|e| warn!("invalid CIDR {} in RECAST_ALLOWED_CIDRS: {}", c, e))
+                            .ok()
+                    })
+                    .collect(),
+                allowed_hosts: split_list(vars.get("RECAST_ALLOWED_HOSTS").as_deref()),
+                rate_limit: vars.parse_or("RECAST_RATE_LIMIT_PER_MINUTE", limits.rate_limit),
+                trust_forwarded_for: vars
+                    .parse_or("RECAST_TRUST_FORWARDED_FOR", limits.trust_forwarded_for),
+                fetch_permits: vars
+                    .parse("RECAST_MAX_CONCURRENCY")
+                    .filter(|&n| n > 0)
+                    .map_or(limits.fetch_permits, |n| Arc::new(Semaphore::new(n))),
+                max_redirects: vars.parse_or("RECAST_MAX_REDIRECTS", limits.max_redirects),
+                allow_now_override: vars
+                    .parse_or("RECAST_ALLOW_NOW_OVERRIDE", limits.allow_now_override),
+                allow_file: vars
+                    .parse_or("RECAST_ALLOW_FILE", Flag(limits.allow_file))
+                    .0,
+                request_timeout: vars
+                    .parse("RECAST_REQUEST_TIMEOUT_SECS")
+                    .filter(|&s| s > 0)
+                    .map_or(limits.request_timeout, std::time::Duration::from_secs),
+            },
+        }
+    }
+}
+
+impl Config {
+    /// The certificate and key to serve HTTPS with, or `None` to serve plain
+    /// HTTP. Setting only one of them is an error rather than falling back to
+    /// HTTP, which would expose traffic meant to be encrypted.
+    pub(crate) fn tls(&self) -> Result<Option<(&Path, &Path)>, String> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            (Some(_), None) => Err("RECAST_TLS_CERT is set but RECAST_TLS_KEY is not".to_string()),
+            (None, Some(_)) => Err("RECAST_TLS_KEY is set but RECAST_TLS_CERT is not".to_string()),
+        }
+    }
+}
+
+/// Looks up configuration variables by name.
+struct Vars<F>(F);
+
+impl<F: Fn(&str) -> Option<String>> Vars<F> {
+    fn get(&self, name: &str) -> Option<String> {
+        (self.0)(name)
+    }
+
+    /// Parses the variable `name`, or returns `None` if it is unset or
+    /// invalid. Invalid values are logged.
+    fn parse<T: std::str::FromStr>(&self, name: &str) -> Option<T>
+    where
+        T::Err: std::fmt::Display,
+    {
+        let v = self.get(name)?;
+        v.parse::<T>()
+            .map_err(|e| warn!("invalid {} {}: {}, using the default", name, v, e))
+            .ok()
+    }
+
+    fn parse_or<T: std::str::FromStr>(&self, name: &str, default: T) -> T
+    where
+        T::Err: std::fmt::Display,
+    {
+        self.parse(name).unwrap_or(default)
+    }
+}
+
+/// A boolean variable that may also be set with `1` or `0`.
+struct Flag(bool);
+
+impl std::str::FromStr for Flag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Flag, Self::Err> {
+        match s {
+            "1" | "true" => Ok(Flag(true)),
+            "0" | "false" => Ok(Flag(false)),
+            _ => Err("expected 1, 0, true or false".to_string()),
+        }
+    }
+}
+
+/// Parses comma-separated origins such as `https://app.example.com`,
+/// dropping invalid ones.
+fn cors_origins(origins: Option<&str>) -> Vec<String> {
+    origins
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .filter_map(|o| match Url::parse(o).map(|u| u.origin()) {
+            Ok(origin) if origin.is_tuple() => Some(origin.ascii_serialization()),
+            _ => {
+                warn!("invalid origin {} in RECAST_CORS_ORIGINS", o);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn from_vars_reads_variables() {
+        let vars: HashMap<_, _> = [
+            ("RECAST_BIND_ADDR", "127.0.0.1:9000"),
+            (
+                "RECAST_CORS_ORIGINS",
+                "https://app.example.com/path, not an origin",
+            ),
+            ("RECAST_ACCESS_LOG_FORMAT", "json"),
+            ("RECAST_MIN_DELAY_MINUTES", "5"),
+            ("RECAST_FETCH_ATTEMPTS", "0"),
+            ("RECAST_CACHE_TTL_SECS", "soon"),
+            ("RECAST_ALLOWED_HOSTS", "Example.com,*.substack.com"),
+            ("RECAST_MAX_CONCURRENCY", "2"),
+            ("RECAST_REQUEST_TIMEOUT_SECS", "10"),
+            ("RECAST_DEFAULT_DELAY", "1d"),
+            ("RECAST_MAX_ITEMS", "100"),
+            ("RECAST_ALLOW_NOW_OVERRIDE", "true"),
+            ("RECAST_ALLOW_FILE", "1"),
+            ("RECAST_CACHE_MAX_AGE_SECS", "600"),
+            ("RECAST_LISTEN_BACKLOG", "4096"),
+            ("RECAST_REUSE_ADDR", "false"),
+        ]
+        .into_iter()
+        .collect();
+
+        let config = Config::from_vars(|name| vars.get(name).map(|v| v.to_string()));
+
+        let default = Config::default();
+        assert_eq!(config.bind_addr, SocketAddr::from(([127, 0, 0, 1], 9000)));
+        assert_eq!(config.cors_origins, ["https://app.example.com"]);
+        assert_eq!(config.listen_backlog, 4096);
+        assert!(!config.reuse_addr);
+        assert_eq!(config.access_log_format, access::Format::Json);
+        assert_eq!(config.limits.min_delay, Duration::minutes(5));
+        assert_eq!(config.limits.max_delay, default.limits.max_delay);
+        assert_eq!(config.limits.default_delay, Some(Duration::days(1)));
+        assert_eq!(config.limits.fetch_attempts, default.limits.fetch_attempts);
+        assert_eq!(config.limits.cache_ttl, default.limits.cache_ttl);
+        assert_eq!(
+            config.limits.allowed_hosts,
+            ["example.com", "*.substack.com"]
+        );
+        assert_eq!(config.limits.fetch_permits.available_permits(), 2);
+        assert_eq!(config.limits.max_items, 100);
+        assert!(config.limits.allow_now_override);
+        assert!(config.limits.allow_file);
+        assert_eq!(
+            config.limits.cache_max_age,
+            std::time::Duration::from_secs(600)
+        );
+        assert_eq!(config.tls(), Ok(None));
+        assert_eq!(
+            config.limits.request_timeout,
+            std::time::Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn allow_file_takes_a_flag() {
+        for (value, allowed) in [
+            ("1", true),
+            ("true", true),
+            ("0", false),
+            ("false", false),
+            ("yes", false),
+        ] {
+            let config =
+                Config::from_vars(|name| (name == "RECAST_ALLOW_FILE").then(|| value.to_string()));
+            assert_eq!(config.limits.allow_file, allowed, "{}", value);
+        }
+    }
+
+    #[test]
+    fn tls_needs_cert_and_key() {
+        let config = Config {
+            tls_cert: Some("cert.pem".into()),
+            ..Config::default()
+        };
+        assert!(config.tls().is_err());
+
+        let config = Config {
+            tls_key: Some("key.pem".into()),
+            ..config
+        };
+        assert_eq!(
+            config.tls(),
+            Ok(Some((Path::new("cert.pem"), Path::new("key.pem"))))
+        );
+    }
+}
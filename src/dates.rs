@@ -0,0 +1,161 @@
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+
+/// Month names and abbreviations in Spanish, French, German, Italian and
+/// Portuguese, with the English abbreviation RFC 2822 expects.
+const MONTHS: &[(&str, &str)] = &[
+    ("ene", "Jan"),
+    ("enero", "Jan"),
+    ("janv", "Jan"),
+    ("janvier", "Jan"),
+    ("januar", "Jan"),
+    ("gen", "Jan"),
+    ("gennaio", "Jan"),
+    ("janeiro", "Jan"),
+    ("febrero", "Feb"),
+    ("févr", "Feb"),
+    ("fevr", "Feb"),
+    ("février", "Feb"),
+    ("februar", "Feb"),
+    ("febbraio", "Feb"),
+    ("fev", "Feb"),
+    ("fevereiro", "Feb"),
+    ("marzo", "Mar"),
+    ("mars", "Mar"),
+    ("mär", "Mar"),
+    ("mrz", "Mar"),
+    ("märz", "Mar"),
+    ("março", "Mar"),
+    ("abr", "Apr"),
+    ("abril", "Apr"),
+    ("avr", "Apr"),
+    ("avril", "Apr"),
+    ("aprile", "Apr"),
+    ("mayo", "May"),
+    ("mai", "May"),
+    ("mag", "May"),
+    ("maggio", "May"),
+    ("maio", "May"),
+    ("junio", "Jun"),
+    ("juin", "Jun"),
+    ("juni", "Jun"),
+    ("giu", "Jun"),
+    ("giugno", "Jun"),
+    ("junho", "Jun"),
+    ("julio", "Jul"),
+    ("juil", "Jul"),
+    ("juillet", "Jul"),
+    ("juli", "Jul"),
+    ("lug", "Jul"),
+    ("luglio", "Jul"),
+    ("julho", "Jul"),
+    ("ago", "Aug"),
+    ("agosto", "Aug"),
+    ("août", "Aug"),
+    ("aout", "Aug"),
+    ("august", "Aug"),
+    ("sept", "Sep"),
+    ("septiembre", "Sep"),
+    ("setiembre", "Sep"),
+    ("septembre", "Sep"),
+    ("set", "Sep"),
+    ("settembre", "Sep"),
+    ("setembro", "Sep"),
+    ("octubre", "Oct"),
+    ("octobre", "Oct"),
+    ("okt", "Oct"),
+    ("oktober", "Oct"),
+    ("ott", "Oct"),
+    ("ottobre", "Oct"),
+    ("out", "Oct"),
+    ("outubro", "Oct"),
+    ("noviembre", "Nov"),
+    ("novembre", "Nov"),
+    ("novembro", "Nov"),
+    ("dic", "Dec"),
+    ("diciembre", "Dec"),
+    ("déc", "Dec"),
+    ("décembre", "Dec"),
+    ("dez", "Dec"),
+    ("dezember", "Dec"),
+    ("dicembre", "Dec"),
+    ("dezembro", "Dec"),
+];
+
+/// Formats tried, after month names are translated, for dates that are
+/// neither RFC 2822 nor RFC 3339.
+const FORMATS: &[&str] = &[
+    "%d %b %Y %H:%M:%S %z",
+    "%d %b %Y %H:%M %z",
+    "%Y-%m-%d %H:%M:%S %z",
+    "%Y-%m-%dT%H:%M:%S%z",
+];
+
+/// Formats without an offset, read as UTC.
+const UTC_FORMATS: &[&str] = &[
+    "%d %b %Y %H:%M:%S",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+];
+
+/// Parses a date that is not quite RFC 2822: one with localized month
+/// names, such as `mar, 14 ene 2020 10:00:00 +0100`, with no or a localized
+/// day of the week, or in one of a few other common formats.
+pub fn parse_lenient(s: &str) -> Option<DateTime<FixedOffset>> {
+    let mut tokens: Vec<_> = s.split_whitespace().collect();
+    // The day of the week is optional, and might not be in English.
+    if tokens.first().is_some_and(|t| t.ends_with(',')) {
+        tokens.remove(0);
+    }
+    let normalized = tokens
+        .iter()
+        .map(|t| {
+            let word = t.trim_end_matches('.').to_lowercase();
+            MONTHS
+                .iter()
+                .find(|(name, _)| *name == word)
+                .map_or(*t, |(_, en)| en)
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    DateTime::parse_from_rfc2822(&normalized)
+        .ok()
+        .or_else(|| {
+            FORMATS
+                .iter()
+                .find_map(|f| DateTime::parse_from_str(&normalized, f).ok())
+        })
+        .or_else(|| {
+            UTC_FORMATS.iter().find_map(|f| {
+                NaiveDateTime::parse_from_str(&normalized, f)
+                    .ok()
+                    .map(|d| Utc.from_utc_datetime(&d).into())
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lenient_formats() {
+        let expected = DateTime::parse_from_rfc3339("2020-01-14T10:00:00+01:00").unwrap();
+        for s in [
+            "mar, 14 ene 2020 10:00:00 +0100",
+            "Mar., 14 Ene. 2020 10:00:00 +0100",
+            "Di, 14 Januar 2020 10:00:00 +0100",
+            "14 janv. 2020 10:00:00 +0100",
+            "14 gen 2020 10:00 +0100",
+            "2020-01-14 10:00:00 +0100",
+            "2020-01-14 09:00:00",
+        ] {
+            assert_eq!(parse_lenient(s), Some(expected), "{}", s);
+        }
+        assert_eq!(
+            parse_lenient("Fri, 14 août 2020 10:00:00 GMT"),
+            DateTime::parse_from_rfc3339("2020-08-14T10:00:00Z").ok()
+        );
+        assert_eq!(parse_lenient("sometime last week"), None);
+    }
+}
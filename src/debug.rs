@@ -0,0 +1,262 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use http::HeaderMap;
+use log::warn;
+use reqwest::Client;
+use serde::Serialize;
+use warp::{Filter, Rejection, Reply};
+
+use crate::{
+    access, atom,
+    cache::FeedCache,
+    ratelimit,
+    rss::{handle_error, load_feed, with_request_timeout},
+    transform::{
+        dedup_key, feed_delay, item_date, item_id, postdate_item, select_items, Dropped, Error,
+        Feed, Limits, Query, RawQuery,
+    },
+};
+
+/// What `/rss.json` reports about a feed.
+#[derive(Serialize, Debug)]
+struct Report {
+    /// The time the report is as of, in RFC 3339.
+    now: String,
+    items: Vec<ItemReport>,
+}
+
+/// What recast parsed of an item and what it does with it.
+#[derive(Serialize, Debug)]
+struct ItemReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    guid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    /// The item's date, if recast can read it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_date: Option<String>,
+    /// The date the item is shown with, or will be once it is due.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_date: Option<String>,
+    kept: bool,
+    /// Why the item is left out, if it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<Reason>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Reason {
+    /// The item fails the keyword, category or enclosure filters.
+    Filtered,
+    Undated,
+    NotYetDue,
+    OutsideAgeWindow,
+    /// An item shown has the same GUID or link.
+    Duplicate,
+    /// Newer items fill `limit`.
+    Limit,
+}
+
+impl Report {
+    /// Reports on each item of `feed` as recasting it with `query` at `now`
+    /// would treat it. Atom entries are reported as the RSS items they
+    /// convert to.
+    fn new(feed: &Feed, query: &Query, now: DateTime<Utc>) -> Report {
+        let channel = match feed {
+            Feed::Rss(channel) => channel.as_ref().clone(),
+            Feed::Atom(feed) => atom::to_channel(feed),
+        };
+        let (shown, _) = select_items(
+            channel.items().iter().cloned(),
+            query,
+            now,
+            |n, item| (n, item_date(&item).map(|(d, _)| d), key(&item)),
+            |(_, date, _)| *date,
+            |(_, _, key)| key.as_deref(),
+        );
+        let shown_keys: HashSet<_> = shown.iter().filter_map(|(_, _, k)| k.clone()).collect();
+        let shown: HashMap<_, _> = shown.into_iter().map(|(n, date, _)| (n, date)).collect();
+
+        let items = channel
+            .items()
+            .iter()
+            .enumerate()
+            .map(|(n, item)| {
+                let original = item_date(item).map(|(d, _)| d);
+                let (new_date, reason) = match shown.get(&n) {
+                    Some(date) => (*date, None),
+                    None => (
+                        original.and_then(|d| query.due_date(d, item_id(item), feed_delay(item))),
+                        Some(reason(item, &shown_keys, query, now)),
+                    ),
+                };
+                ItemReport {
+                    guid: item.guid().map(|g| g.value().to_string()),
+                    title: item.title().map(str::to_string),
+                    original_date: original.as_ref().map(DateTime::to_rfc3339),
+                    new_date: new_date.as_ref().map(DateTime::<FixedOffset>::to_rfc3339),
+                    kept: reason.is_none(),
+                    reason,
+                }
+            })
+            .collect();
+        Report {
+            now: now.to_rfc3339(),
+            items,
+        }
+    }
+}
+
+/// What `dedup` compares items by.
+fn key(item: &rss::Item) -> Option<String> {
+    dedup_key(item).map(str::to_string)
+}
+
+/// Why `item`, which is not shown, is left out.
+fn reason(
+    item: &rss::Item,
+    shown_keys: &HashSet<String>,
+    query: &Query,
+    now: DateTime<Utc>,
+) -> Reason {
+    if !query.matches(item) {
+        return Reason::Filtered;
+    }
+    match postdate_item(&mut item.clone(), query, now) {
+        Err(Dropped::Undated) if !query.keep_undated => Reason::Undated,
+        Err(Dropped::NotYetDue) => Reason::NotYetDue,
+        Err(Dropped::OutsideAgeWindow) => Reason::OutsideAgeWindow,
+        _ if query.dedup && key(item).is_some_and(|k| shown_keys.contains(&k)) => Reason::Duplicate,
+        _ => Reason::Limit,
+    }
+}
+
+/// The `/rss.json` route, which takes the same query as `/rss` and reports,
+/// instead of the feed, what recast parsed of each item, the date it
+/// computed and whether the item is shown.
+pub(crate) fn route(
+    limits: Limits,
+    client: Client,
+    cache: FeedCache,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("rss.json"))
+        .and(ratelimit::limit(&limits))
+        .and(access::request_id())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::headers_cloned())
+        .and(warp::any().map(move || limits.clone()))
+        .and(warp::any().map(move || client.clone()))
+        .and(warp::any().map(move || cache.clone()))
+        .and_then(handler)
+        .recover(handle_error)
+}
+
+async fn handler(
+    id: String,
+    r_query: String,
+    headers: HeaderMap,
+    limits: Limits,
+    client: Client,
+    cache: FeedCache,
+) -> Result<impl Reply, Rejection> {
+    let budget = limits.request_timeout;
+    access::traced(
+        id,
+        with_request_timeout(budget, async move {
+            let query: Query = r_query
+                .parse::<RawQuery>()
+                .and_then(|q| (q, &limits).try_into())
+                .and_then(|q: Query| match q.urls.len() {
+                    1 => Ok(q),
+                    _ => Err("only one url can be debugged at a time".to_string()),
+                })
+                .map_err(|e: String| {
+                    warn!("failed to parse query: {}", e);
+                    warp::reject::custom(Error::QueryParse(e))
+                })?;
+            let user_agent = headers
+                .get(http::header::USER_AGENT)
+                .and_then(|ua| ua.to_str().ok());
+            let (_, feed) = load_feed(&client, &cache, &query.urls[0], user_agent, &limits)
+                .await
+                .map_err(warp::reject::custom)?;
+            Ok(warp::reply::json(&Report::new(&feed, &query, query.now())))
+        }),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use super::*;
+    use crate::fetch::build_client;
+
+    const FEED: &str = r#"<rss version="2.0"><channel><title>Example</title><link>http://example.com</link><description>d</description>
+<item><title>Due</title><guid>a</guid><pubDate>Thu, 02 Jan 2020 00:00:00 +0000</pubDate></item>
+<item><title>Again</title><guid>a</guid><pubDate>Wed, 01 Jan 2020 00:00:00 +0000</pubDate></item>
+<item><title>Sponsored</title><guid>s</guid><pubDate>Wed, 01 Jan 2020 00:00:00 +0000</pubDate></item>
+<item><title>Undated</title><guid>u</guid></item>
+<item><title>Future</title><guid>f</guid><pubDate>Tue, 01 Jan 2999 00:00:00 +0000</pubDate></item>
+<item><title>Older</title><guid>o</guid><pubDate>Tue, 01 Jan 2019 00:00:00 +0000</pubDate></item>
+</channel></rss>"#;
+
+    #[tokio::test]
+    async fn debug_reports_items() {
+        let (addr, server) =
+            warp::serve(warp::any().map(|| FEED)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let client = build_client(&limits).unwrap();
+        let cache = FeedCache::new(limits.cache_ttl);
+        let url = urlencoding::encode(&format!("http://{}/rss.xml", addr)).into_owned();
+
+        let res = warp::test::request()
+            .path(&format!(
+                "/rss.json?url={}&delay=1&dedup=true&exclude=sponsored&limit=1",
+                url
+            ))
+            .reply(&route(limits.clone(), client.clone(), cache.clone()))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let items = body["items"].as_array().unwrap();
+        let reasons: Vec<_> = items.iter().map(|i| &i["reason"]).collect();
+        assert_eq!(
+            reasons,
+            [
+                &serde_json::Value::Null,
+                &serde_json::json!("duplicate"),
+                &serde_json::json!("filtered"),
+                &serde_json::json!("undated"),
+                &serde_json::json!("not_yet_due"),
+                &serde_json::json!("limit"),
+            ]
+        );
+        assert_eq!(
+            items[0],
+            serde_json::json!({
+                "guid": "a",
+                "title": "Due",
+                "original_date": "2020-01-02T00:00:00+00:00",
+                "new_date": "2020-01-02T01:00:00+00:00",
+                "kept": true,
+            })
+        );
+        assert_eq!(items[4]["new_date"], "2999-01-01T01:00:00+00:00");
+        assert_eq!(items[4]["kept"], false);
+
+        let res = warp::test::request()
+            .path(&format!("/rss.json?url={},{}&delay=1", url, url))
+            .reply(&route(limits, client, cache))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
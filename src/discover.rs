@@ -0,0 +1,105 @@
+/// The types of `<link rel="alternate">` tags that point at feeds.
+const FEED_TYPES: &[&str] = &["application/rss+xml", "application/atom+xml"];
+
+/// The `href` of the first `<link rel="alternate">` to a feed in `html`, as
+/// written in the page, so possibly relative.
+pub(crate) fn feed_link(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut rest = 0;
+    while let Some(start) = lower[rest..].find("<link").map(|i| rest + i) {
+        let end = lower[start..].find('>').map_or(lower.len(), |i| start + i);
+        let attrs = attributes(&html[start + "<link".len()..end]);
+        rest = end;
+
+        let attr = |name: &str| {
+            attrs
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| *v)
+        };
+        let alternate = attr("rel").is_some_and(|rel| {
+            rel.split_ascii_whitespace()
+                .any(|r| r.eq_ignore_ascii_case("alternate"))
+        });
+        let feed = attr("type")
+            .is_some_and(|t| FEED_TYPES.iter().any(|f| t.trim().eq_ignore_ascii_case(f)));
+        match attr("href") {
+            Some(href) if alternate && feed && !href.trim().is_empty() => {
+                return Some(unescape(href.trim()))
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The attributes of a tag, given what follows its name, with their values
+/// unquoted. Attributes without a value are left out.
+fn attributes(tag: &str) -> Vec<(&str, &str)> {
+    let mut attrs = Vec::new();
+    let mut rest = tag;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        if rest.is_empty() {
+            return attrs;
+        }
+        let name_len = rest
+            .find(|c: char| c == '=' || c.is_whitespace() || c == '/')
+            .unwrap_or(rest.len());
+        let name = &rest[..name_len];
+        rest = rest[name_len..].trim_start();
+        let raw = match rest.strip_prefix('=') {
+            Some(raw) => raw.trim_start(),
+            None => continue,
+        };
+        let (value, len) = match raw.chars().next() {
+            Some(q @ ('"' | '\'')) => match raw[1..].find(q) {
+                Some(len) => (&raw[1..1 + len], len + 2),
+                None => (&raw[1..], raw.len()),
+            },
+            _ => {
+                let len = raw.find(char::is_whitespace).unwrap_or(raw.len());
+                (&raw[..len], len)
+            }
+        };
+        attrs.push((name, value));
+        rest = &raw[len..];
+    }
+}
+
+/// Decodes the character references that commonly appear in URLs.
+fn unescape(href: &str) -> String {
+    href.replace("&amp;", "&")
+        .replace("&#38;", "&")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feed_link_finds_first_feed() {
+        let page = r#"<!DOCTYPE html><html><head>
+<link rel="stylesheet" href="/style.css">
+<link rel="alternate" type="text/html" hreflang="fr" href="/fr/">
+<LINK REL="Alternate" TYPE="application/rss+xml" title="Posts" HREF='/feed.xml?a=1&amp;b=2'>
+<link rel="alternate" type="application/atom+xml" href="/atom.xml" />
+</head></html>"#;
+        assert_eq!(feed_link(page).as_deref(), Some("/feed.xml?a=1&b=2"));
+
+        let page =
+            r#"<link href=https://example.com/atom.xml rel=alternate type=application/atom+xml>"#;
+        assert_eq!(
+            feed_link(page).as_deref(),
+            Some("https://example.com/atom.xml")
+        );
+
+        assert_eq!(feed_link("<html><body>No feeds</body></html>"), None);
+        assert_eq!(
+            feed_link(r#"<link rel="alternate" type="application/rss+xml">"#),
+            None
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use chrono::DateTime;
+use rss::Channel;
+use serde::Serialize;
+
+use crate::transform::{item_date, item_id, original_pub_date};
+
+/// A [JSON Feed 1.1](https://www.jsonfeed.org/version/1.1/) document.
+#[derive(Serialize, Debug)]
+pub(crate) struct JsonFeed {
+    version: &'static str,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    home_page_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    items: Vec<JsonItem>,
+}
+
+#[derive(Serialize, Debug)]
+struct JsonItem {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    content_html: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_published: Option<String>,
+    /// recast's own fields, as a JSON Feed extension.
+    #[serde(rename = "_recast", skip_serializing_if = "Option::is_none")]
+    recast: Option<JsonRecast>,
+}
+
+#[derive(Serialize, Debug)]
+struct JsonRecast {
+    /// The date the item was originally published, from its
+    /// `recast:originalPubDate`.
+    original_date_published: String,
+}
+
+impl From<&Channel> for JsonFeed {
+    fn from(channel: &Channel) -> JsonFeed {
+        let non_empty = |s: &str| Some(s.to_string()).filter(|s| !s.is_empty());
+        JsonFeed {
+            version: "https://jsonfeed.org/version/1.1",
+            title: channel.title().to_string(),
+            home_page_url: non_empty(channel.link()),
+            description: non_empty(channel.description()),
+            items: channel
+                .items()
+                .iter()
+                .map(|i| JsonItem {
+                    id: item_id(i).to_string(),
+                    url: i.link().map(str::to_string),
+                    title: i.title().map(str::to_string),
+                    content_html: i.description().unwrap_or_default().to_string(),
+                    date_published: item_date(i).map(|(d, _)| d.to_rfc3339()),
+                    recast: original_pub_date(i)
+                        .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+                        .map(|d| JsonRecast {
+                            original_date_published: d.to_rfc3339(),
+                        }),
+                })
+                .collect(),
+        }
+    }
+}
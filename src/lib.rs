@@ -0,0 +1,45 @@
+//! recast reruns RSS and Atom feeds: it delays their items by a set time, as
+//! if the feed were still being published.
+//!
+//! The transform itself does no I/O. Parse a query with `RawQuery::from_str`
+//! and `Query::try_from`, then recast fetched feeds with `process_channel`.
+//! The HTTP server that fetches feeds for it is behind the `server` feature,
+//! on by default.
+
+// Some of the transform is only used by the server.
+#![cfg_attr(not(feature = "server"), allow(dead_code))]
+
+mod atom;
+pub mod dates;
+mod discover;
+mod json;
+mod preview;
+mod stream;
+mod transform;
+
+#[cfg(feature = "server")]
+mod access;
+#[cfg(feature = "server")]
+mod cache;
+#[cfg(feature = "server")]
+mod compress;
+#[cfg(feature = "server")]
+mod config;
+#[cfg(feature = "server")]
+mod debug;
+#[cfg(feature = "server")]
+mod fetch;
+#[cfg(feature = "server")]
+mod metrics;
+#[cfg(feature = "server")]
+mod opml;
+#[cfg(feature = "server")]
+mod ratelimit;
+#[cfg(feature = "server")]
+mod rss;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+mod validate;
+
+pub use transform::{compare_time_after_delay, process_channel, Error, Limits, Query, RawQuery};
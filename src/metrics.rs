@@ -0,0 +1,39 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, Encoder, Histogram,
+    IntCounter, IntCounterVec, TextEncoder,
+};
+
+lazy_static! {
+    pub(crate) static ref REQUESTS: IntCounter =
+        register_int_counter!("recast_requests_total", "Number of feed requests received.")
+            .unwrap();
+    pub(crate) static ref ERRORS: IntCounterVec = register_int_counter_vec!(
+        "recast_errors_total",
+        "Number of failed feed requests, by error.",
+        &["error"]
+    )
+    .unwrap();
+    pub(crate) static ref FETCH_DURATION: Histogram = register_histogram!(
+        "recast_fetch_duration_seconds",
+        "Time taken by each attempt to fetch an upstream feed."
+    )
+    .unwrap();
+}
+
+/// Registers all metrics with the default registry, so they are exported
+/// even before they are first updated.
+pub(crate) fn init() {
+    lazy_static::initialize(&REQUESTS);
+    lazy_static::initialize(&ERRORS);
+    lazy_static::initialize(&FETCH_DURATION);
+}
+
+/// Renders the default registry in the Prometheus text format.
+pub(crate) fn render() -> String {
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .expect("metrics encoding cannot fail");
+    String::from_utf8(buf).expect("metrics are valid UTF-8")
+}
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use http::HeaderMap;
+use log::warn;
+use quick_xml::{events::Event, Reader};
+use reqwest::Client;
+use url::form_urlencoded;
+use warp::{path::FullPath, reply::Response, Filter, Rejection, Reply};
+
+use crate::{
+    access,
+    cache::FeedCache,
+    fetch::redact,
+    metrics, ratelimit,
+    rss::{autodiscovered, handle_error, load_feeds, recast, recast_link, with_request_timeout},
+    transform::{Error, Limits, Query, RawQuery},
+};
+
+/// The `POST /opml` route, which recasts every feed in an uploaded OPML
+/// document with the options in the query string.
+pub(crate) fn route(
+    limits: Limits,
+    client: Client,
+    cache: FeedCache,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("opml"))
+        .and(ratelimit::limit(&limits))
+        .and(access::request_id())
+        .and(warp::header::<String>(http::header::HOST.as_str()))
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::bytes())
+        .and(warp::header::headers_cloned())
+        .and(warp::any().map(move || limits.clone()))
+        .and(warp::any().map(move || client.clone()))
+        .and(warp::any().map(move || cache.clone()))
+        .and_then(handler)
+        .recover(handle_error)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handler(
+    id: String,
+    host: String,
+    path: FullPath,
+    r_query: String,
+    body: Bytes,
+    headers: HeaderMap,
+    limits: Limits,
+    client: Client,
+    cache: FeedCache,
+) -> Result<Response, Rejection> {
+    metrics::REQUESTS.inc();
+    let budget = limits.request_timeout;
+    access::traced(
+        id,
+        with_request_timeout(budget, async move {
+            let urls = feed_urls(&body).map_err(|e| {
+                warn!("failed to parse OPML: {}", e);
+                warp::reject::custom(Error::OpmlParse(e))
+            })?;
+            let report = form_urlencoded::parse(r_query.as_bytes())
+                .any(|(k, v)| k == "report" && v == "true");
+            let query: Query = r_query
+                .parse::<RawQuery>()
+                .map(|mut q: RawQuery| {
+                    q.url = urls;
+                    q
+                })
+                .and_then(|q| (q, &limits).try_into())
+                .map_err(|e: String| {
+                    warn!("failed to parse query: {}", e);
+                    warp::reject::custom(Error::QueryParse(e))
+                })?;
+
+            if report {
+                let report = status_report(&query, &headers, &limits, &client, &cache).await;
+                return Ok(warp::reply::json(&report).into_response());
+            }
+            let link = recast_link(&host, path.as_str(), &r_query);
+            Ok(recast(query, link, &headers, &limits, &client, &cache)
+                .await?
+                .into_response())
+        }),
+    )
+    .await
+}
+
+/// Loads every feed in `query`, mapping each URL to `ok` or the code of the
+/// error it failed with.
+async fn status_report(
+    query: &Query,
+    headers: &HeaderMap,
+    limits: &Limits,
+    client: &Client,
+    cache: &FeedCache,
+) -> BTreeMap<String, &'static str> {
+    let user_agent = headers
+        .get(http::header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok());
+    let results = load_feeds(client, cache, &query.urls, user_agent, limits).await;
+    let mut report = BTreeMap::new();
+    for (url, res) in query.urls.iter().zip(results) {
+        let (_, res) = autodiscovered(
+            res,
+            url,
+            query.autodiscover,
+            client,
+            cache,
+            user_agent,
+            limits,
+        )
+        .await;
+        let status = match res {
+            Ok(_) => "ok",
+            Err(e) => e.code(),
+        };
+        report.insert(redact(url), status);
+    }
+    report
+}
+
+/// The `xmlUrl` of every outline in an OPML document.
+fn feed_urls(opml: &[u8]) -> Result<Vec<String>, String> {
+    let mut reader = Reader::from_reader(opml);
+    let mut buf = Vec::new();
+    let mut urls = Vec::new();
+    let mut saw_opml = false;
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => match e.name() {
+                b"opml" => saw_opml = true,
+                b"outline" => {
+                    for attr in e.attributes() {
+                        let attr = attr.map_err(|e| e.to_string())?;
+                        if attr.key == b"xmlUrl" {
+                            let url = attr
+                                .unescape_and_decode_value(&reader)
+                                .map_err(|e| e.to_string())?;
+                            urls.push(url);
+                        }
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => {
+                return Err(format!(
+                    "error at position {}: {}",
+                    reader.buffer_position(),
+                    e
+                ))
+            }
+        }
+        buf.clear();
+    }
+    if !saw_opml {
+        return Err("not an OPML document".to_string());
+    }
+    if urls.is_empty() {
+        return Err("no outlines with an xmlUrl".to_string());
+    }
+    Ok(urls)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use super::*;
+    use crate::fetch::build_client;
+
+    const FEED: &str = r#"<rss version="2.0"><channel><title>Example</title><link>http://example.com</link><description>d</description>
+<item><title>Old</title><pubDate>Mon, 01 Jan 2024 00:00:00 +0000</pubDate></item>
+</channel></rss>"#;
+
+    fn test_route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let client = build_client(&limits).unwrap();
+        let cache = FeedCache::new(limits.cache_ttl);
+        route(limits, client, cache)
+    }
+
+    #[tokio::test]
+    async fn opml_recasts_every_feed() {
+        let feed = warp::path!("rss.xml").map(|| FEED);
+        let (addr, server) = warp::serve(feed).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let opml = format!(
+            r#"<?xml version="1.0"?>
+<opml version="2.0"><head><title>Subscriptions</title></head><body>
+<outline text="Podcasts">
+<outline text="A" type="rss" xmlUrl="http://{0}/rss.xml"/>
+<outline text="B" type="rss" xmlUrl="http://{0}/missing.xml"/>
+</outline>
+</body></opml>"#,
+            addr
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/opml?delay=1h&report=true")
+            .header("host", "localhost")
+            .body(&opml)
+            .reply(&test_route())
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                format!("http://{}/rss.xml", addr): "ok",
+                format!("http://{}/missing.xml", addr): "upstream_status",
+            })
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/opml?delay=1h")
+            .header("host", "localhost")
+            .body(&opml)
+            .reply(&test_route())
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = std::str::from_utf8(res.body()).unwrap();
+        assert!(body.contains("<title>Old</title>"), "{}", body);
+        assert!(res.headers().contains_key("x-recast-warning"));
+    }
+
+    #[tokio::test]
+    async fn opml_400_malformed() {
+        for body in ["<opml><body><outline xmlUrl=", "<rss></rss>"] {
+            let res = warp::test::request()
+                .method("POST")
+                .path("/opml?delay=1h")
+                .header("host", "localhost")
+                .body(body)
+                .reply(&test_route())
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", body);
+            let error: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(error["error"], "opml_parse");
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use serde::Serialize;
+
+use crate::transform::{
+    compare_time_after_delay, feed_delay, item_date, item_id, Direction, Feed, Query,
+};
+
+/// How a delay affects a feed: what `/rss?preview=true` reports instead of
+/// the feed.
+#[derive(Serialize, Debug)]
+pub(crate) struct Preview {
+    total: usize,
+    /// Items that are due, so would be shown.
+    visible: usize,
+    /// Items that are not yet due.
+    hidden: usize,
+    /// Items without a date recast can read.
+    undated: usize,
+    /// When the next hidden item becomes due.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_visible_at: Option<String>,
+    #[serde(skip)]
+    next_due: Option<DateTime<FixedOffset>>,
+}
+
+impl Preview {
+    pub(crate) fn new(feed: &Feed, query: &Query, now: DateTime<Utc>) -> Preview {
+        type Dates<'a> = Vec<(Option<DateTime<FixedOffset>>, &'a str, Option<Duration>)>;
+        let dates: Dates = match feed {
+            Feed::Rss(channel) => channel
+                .items()
+                .iter()
+                .map(|i| (item_date(i).map(|(d, _)| d), item_id(i), feed_delay(i)))
+                .collect(),
+            Feed::Atom(feed) => feed
+                .entries
+                .iter()
+                .map(|e| (Some(e.published.unwrap_or(e.updated)), e.id.as_str(), None))
+                .collect(),
+        };
+
+        let mut preview = Preview {
+            total: dates.len(),
+            visible: 0,
+            hidden: 0,
+            undated: 0,
+            next_visible_at: None,
+            next_due: None,
+        };
+        let mut next = None;
+        for (date, id, feed_delay) in dates {
+            let (date, due) = match date.and_then(|d| Some((d, query.due_date(d, id, feed_delay)?)))
+            {
+                Some(d) => d,
+                None => {
+                    preview.undated += 1;
+                    continue;
+                }
+            };
+            if query.direction == Direction::Back
+                || compare_time_after_delay(date, due - date, now).is_some()
+            {
+                preview.visible += 1;
+            } else {
+                preview.hidden += 1;
+                next = Some(next.map_or(due, |n: DateTime<FixedOffset>| n.min(due)));
+            }
+        }
+        preview.next_visible_at = next.map(|d| d.to_rfc3339());
+        preview.next_due = next;
+        preview
+    }
+
+    /// When the next hidden item becomes due, changing what is shown.
+    pub(crate) fn next_due(&self) -> Option<DateTime<FixedOffset>> {
+        self.next_due
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use http::HeaderMap;
+use log::warn;
+use warp::{Filter, Rejection};
+
+use crate::{
+    server::PeerAddr,
+    transform::{Error, Limits},
+};
+
+/// Past this many tracked clients, clients whose buckets have refilled are
+/// forgotten.
+const MAX_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket rate limiter keyed by client IP. Each client may make
+/// `per_minute` requests in a burst, and regains one request every
+/// `60 / per_minute` seconds. Cloning it yields a handle to the same state.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    per_minute: u32,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(per_minute: u32) -> RateLimiter {
+        RateLimiter {
+            per_minute,
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Takes a token for a request from `ip`, or returns how many seconds
+    /// until one is available.
+    fn check(&self, ip: IpAddr, now: Instant) -> Result<(), u64> {
+        let capacity = f64::from(self.per_minute);
+        let per_sec = capacity / 60.0;
+        let refilled = |b: &Bucket| {
+            (b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * per_sec)
+                .min(capacity)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS {
+            buckets.retain(|_, b| refilled(b) < capacity);
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refilled(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / per_sec).ceil() as u64)
+        }
+    }
+}
+
+/// Rejects requests with `Error::RateLimited` once their client exceeds
+/// `limits.rate_limit` requests per minute. The client is identified by its
+/// address, as warp or `server::serve` accepted it, or by the last
+/// `X-Forwarded-For` entry if `limits.trust_forwarded_for` is set: the one
+/// the proxy in front added, since the client can send any others. Does
+/// nothing if `rate_limit` is 0.
+pub(crate) fn limit(
+    limits: &Limits,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone + Send + Sync + 'static {
+    let limiter = (limits.rate_limit > 0).then(|| RateLimiter::new(limits.rate_limit));
+    let trust_forwarded_for = limits.trust_forwarded_for;
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .map(|remote: Option<SocketAddr>, peer: Option<PeerAddr>| remote.or(peer.map(|p| p.0)))
+        .and(warp::header::headers_cloned())
+        .and_then(move |remote: Option<SocketAddr>, headers: HeaderMap| {
+            let limiter = limiter.clone();
+            async move {
+                let limiter = match limiter {
+                    Some(l) => l,
+                    None => return Ok(()),
+                };
+                let forwarded = headers
+                    .get_all("x-forwarded-for")
+                    .iter()
+                    .next_back()
+                    .filter(|_| trust_forwarded_for)
+                    .and_then(|f| f.to_str().ok()?.rsplit(',').next()?.trim().parse().ok());
+                let ip = match forwarded.or_else(|| remote.map(|r| r.ip())) {
+                    Some(ip) => ip,
+                    None => return Ok(()),
+                };
+                limiter.check(ip, Instant::now()).map_err(|secs| {
+                    warn!("rate limiting {}", ip);
+                    warp::reject::custom(Error::RateLimited(secs))
+                })
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn check_allows_bursts_then_refills() {
+        let limiter = RateLimiter::new(2);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(ip, now), Ok(()));
+        assert_eq!(limiter.check(ip, now), Ok(()));
+        assert_eq!(limiter.check(ip, now), Err(30));
+        assert_eq!(limiter.check(other, now), Ok(()));
+        assert_eq!(limiter.check(ip, now + Duration::from_secs(30)), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn limit_keys_on_the_address_the_proxy_added() {
+        let limits = Limits {
+            rate_limit: 1,
+            trust_forwarded_for: true,
+            ..Limits::default()
+        };
+        let filter = limit(&limits);
+        let allowed = |forwarded_for: &str| {
+            warp::test::request()
+                .remote_addr(([127, 0, 0, 1], 1234).into())
+                .header("x-forwarded-for", forwarded_for)
+                .filter(&filter)
+        };
+
+        assert!(allowed("198.51.100.1, 192.0.2.1").await.is_ok());
+        // A client making up the entries before the proxy's gets no fresh
+        // bucket.
+        assert!(allowed("198.51.100.2, 192.0.2.1").await.is_err());
+        assert!(allowed("192.0.2.2").await.is_ok());
+    }
+}
@@ -1,220 +1,1966 @@
-use std::convert::Infallible;
+use std::{
+    collections::hash_map::DefaultHasher,
+    future::Future,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
 
-use chrono::{DateTime, Duration, TimeZone};
-use chrono_humanize::{Accuracy, HumanTime, Tense};
-use http::{Response, StatusCode};
-use log::warn;
-use rss::{Channel, Item};
+use bytes::Bytes;
+use chrono::{DateTime, FixedOffset, Utc};
+use futures::future::join_all;
+use http::{HeaderMap, HeaderValue, Response, StatusCode};
+use log::{info, warn};
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use urlencoding::decode;
-use warp::{path::FullPath, Rejection, Reply};
+use url::Url;
+use warp::{path::FullPath, Filter, Rejection, Reply};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct RawQuery {
-    url: String,
-    delay: String,
-}
+use crate::{
+    access, atom,
+    cache::{Entry, FeedCache},
+    fetch::{
+        check_host, check_target, fetch_feed, is_file_url, read_file, redact, redact_query,
+        Fetched, SOURCE_URL_HEADER,
+    },
+    metrics,
+    preview::Preview,
+    ratelimit,
+    transform::{
+        absolutize, fill_required, item_date, item_id, merge_feeds, order_items, parse_feed,
+        process_feed, render, transform_channel, update_pub_date, utf8_content_type,
+        with_feed_delay, Direction, Drops, Error, Feed, Format, Limits, Query, RawQuery, Sort,
+    },
+};
 
-struct Query {
-    url: String,
-    delay: chrono::Duration,
+/// The name of the response header listing feeds that failed to load when
+/// merging several feeds.
+const WARNING_HEADER: &str = "x-recast-warning";
+/// Set by `load_feed` on the headers of a feed it cut down to
+/// `RECAST_MAX_ITEMS` items, to how many items the feed had.
+const TRUNCATED_HEADER: &str = "x-recast-truncated-items";
+
+/// The `/rss` route, answering errors as well as feeds.
+pub(crate) fn route(
+    limits: Limits,
+    client: Client,
+    cache: FeedCache,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("rss"))
+        .and(ratelimit::limit(&limits))
+        .and(access::request_id())
+        .and(warp::header::<String>(http::header::HOST.as_str()))
+        .and(warp::path::full())
+        // A request without a query gets the same errors as an empty one.
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::headers_cloned())
+        .and(warp::any().map(move || limits.clone()))
+        .and(warp::any().map(move || client.clone()))
+        .and(warp::any().map(move || cache.clone()))
+        .and_then(handler)
+        .recover(handle_error)
 }
 
-impl Query {
-    fn min_delay() -> chrono::Duration {
-        Duration::hours(1)
+/// A weak ETag over the items of a transformed feed: their GUIDs and dates,
+/// to the minute. Unlike the body, which has a new `lastBuildDate` every
+/// time, it only changes when the items shown do, such as when one becomes
+/// due.
+fn items_etag(feed: &Feed) -> String {
+    let mut hasher = DefaultHasher::new();
+    match feed {
+        Feed::Rss(channel) => {
+            for item in channel.items() {
+                item_id(item).hash(&mut hasher);
+                item_date(item)
+                    .map(|(d, _)| d.timestamp() / 60)
+                    .hash(&mut hasher);
+            }
+        }
+        Feed::Atom(feed) => {
+            for entry in feed.entries() {
+                entry.id.hash(&mut hasher);
+                (entry.published.unwrap_or(entry.updated).timestamp() / 60).hash(&mut hasher);
+            }
+        }
     }
+    format!("W/\"{:016x}\"", hasher.finish())
 }
 
-impl TryFrom<RawQuery> for Query {
-    type Error = String;
+/// How long, in seconds, clients and caches may reuse a feed recast as of
+/// `now` before what it shows can change: until `next_due`, when the next
+/// hidden item becomes due, and no longer than the delay, as an item
+/// published from now on is not shown any sooner. With `clamp` or
+/// `direction=back`, such items are shown at once, so the feed may change
+/// whenever recast refetches it.
+/// Never longer than `Limits::cache_max_age`.
+fn max_age(
+    query: &Query,
+    next_due: Option<DateTime<FixedOffset>>,
+    now: DateTime<Utc>,
+    limits: &Limits,
+) -> u64 {
+    let secs = |d: chrono::Duration| d.num_seconds().max(0) as u64;
+    let mut age = limits.cache_max_age.as_secs().min(secs(query.delay));
+    if query.clamp || query.direction == Direction::Back {
+        age = age.min(limits.cache_ttl.as_secs());
+    }
+    if let Some(due) = next_due {
+        age = age.min(secs(due.with_timezone(&Utc) - now));
+    }
+    age
+}
 
-    fn try_from(value: RawQuery) -> Result<Query, Self::Error> {
-        let url = decode(&value.url)
-            .map_err(|e| format!("failed to decode URL {}: {}", &value.url, e))?
-            .into_owned();
-        let delay = match value.delay.parse::<i64>() {
-            Ok(d) => {
-                let dh = chrono::Duration::hours(d);
-                if dh < Query::min_delay() {
-                    return Err(format!(
-                        "delay must be at least {}",
-                        Query::min_delay().num_hours()
-                    ));
-                } else {
-                    dh
-                }
-            }
-            Err(e) => {
-                return Err(format!("delay must be an integer: {}", e));
-            }
-        };
+/// Whether the `If-None-Match` header value `tags` matches `etag`, comparing
+/// weakly as `If-None-Match` does.
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    let opaque = |t: &str| t.trim().trim_start_matches("W/").to_string();
+    tags.split(',')
+        .any(|t| t.trim() == "*" || opaque(t) == opaque(etag))
+}
 
-        Ok(Query { url, delay })
+/// Whether the client's copy, named by its `If-None-Match` or else its
+/// `If-Modified-Since`, is still current. `etag` and `last_modified` are
+/// recast's own, since the source feed's say nothing of when its items
+/// become due.
+fn is_not_modified(headers: &HeaderMap, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    match header(http::header::IF_NONE_MATCH) {
+        Some(tags) => etag_matches(tags, etag),
+        None => header(http::header::IF_MODIFIED_SINCE)
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+            .is_some_and(|since| last_modified <= since),
     }
 }
 
+/// `date` as an HTTP date, such as `Thu, 02 Jan 2020 03:04:05 GMT`.
+fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn handler(
+    id: String,
     host: String,
     path: FullPath,
     r_query: String,
-    query: RawQuery,
+    headers: HeaderMap,
+    limits: Limits,
+    client: Client,
+    cache: FeedCache,
 ) -> Result<impl Reply, Rejection> {
-    let query: Query = query.try_into().map_err(|e: String| {
-        warn!("failed to parse query: {}", e);
-        warp::reject::custom(Error::QueryParse(e))
-    })?;
-    let url = query.url;
-    let delay = query.delay;
-
-    let res = reqwest::get(&url).await.map_err(|e| {
-        warn!("failed to load feed: {}", e);
-        warp::reject::custom(Error::FeedLoad(e.to_string()))
-    })?;
-
-    let h = res.headers().clone();
-
-    let content = res.bytes().await.map_err(|e| {
-        warn!("failed to read feed: {}", e);
-        warp::reject::custom(Error::FeedLoad(e.to_string()))
-    })?;
-
-    let mut channel = Channel::read_from(&content[..]).map_err(|e| {
-        warn!("failed to parse feed: {}", e);
-        warp::reject::custom(Error::FeedParse(e.to_string()))
-    })?;
-
-    update_title(&mut channel, delay);
-    update_link(&mut channel, &host, path.as_str(), &r_query);
-
-    let new_items: Vec<Item> = channel
-        .items_mut()
-        .iter_mut()
-        .filter_map(|i| postdate_item(i, delay))
+    metrics::REQUESTS.inc();
+    let budget = limits.request_timeout;
+    access::traced(
+        id,
+        with_request_timeout(budget, async move {
+            let query: Query = r_query
+                .parse::<RawQuery>()
+                .and_then(|q| (q, &limits).try_into())
+                .map_err(|e: String| {
+                    warn!("failed to parse query: {}", e);
+                    warp::reject::custom(Error::QueryParse(e))
+                })?;
+            let link = recast_link(&host, path.as_str(), &r_query);
+            recast(query, link, &headers, &limits, &client, &cache).await
+        }),
+    )
+    .await
+}
+
+/// Runs `handling`, giving up with `Error::RequestTimeout` once it has taken
+/// longer than `budget`. This bounds every request however slow its stages
+/// are, on top of the timeout for fetching each feed.
+pub(crate) async fn with_request_timeout<T>(
+    budget: std::time::Duration,
+    handling: impl Future<Output = Result<T, Rejection>>,
+) -> Result<T, Rejection> {
+    tokio::time::timeout(budget, handling)
+        .await
+        .unwrap_or_else(|_| {
+            warn!("request timed out after {:?}", budget);
+            Err(warp::reject::custom(Error::RequestTimeout(
+                budget.as_secs(),
+            )))
+        })
+}
+
+/// Loads the feeds `query` asks for and recasts them into one, linking to
+/// itself with `link`.
+pub(crate) async fn recast(
+    query: Query,
+    link: String,
+    headers: &HeaderMap,
+    limits: &Limits,
+    client: &Client,
+    cache: &FeedCache,
+) -> Result<impl Reply, Rejection> {
+    let user_agent = headers
+        .get(http::header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok());
+
+    let mut feeds = Vec::new();
+    let mut failures = Vec::new();
+    for (i, (url, res)) in query
+        .urls
+        .iter()
+        .zip(load_feeds(client, cache, &query.urls, user_agent, limits).await)
+        .enumerate()
+    {
+        let (feed_url, res) = autodiscovered(
+            res,
+            url,
+            query.autodiscover,
+            client,
+            cache,
+            user_agent,
+            limits,
+        )
+        .await;
+        match res {
+            Ok((h, mut feed)) => {
+                fill_required(&mut feed, &redact(&feed_url));
+                if query.absolutize {
+                    absolutize(&mut feed, &redact(&feed_url));
+                }
+                // Merged feeds may share item IDs, so each item carries the
+                // delay of its own feed.
+                if let Some(&delay) = query.delays.get(i) {
+                    feed = with_feed_delay(feed, delay);
+                }
+                feeds.push((h, feed))
+            }
+            Err(e) => failures.push((url, e)),
+        }
+    }
+    if feeds.is_empty() {
+        let (_, e) = failures.remove(0);
+        return Err(warp::reject::custom(e));
+    }
+    let h = feeds[0].0.clone();
+    let sources: Vec<_> = feeds
+        .iter()
+        .filter_map(|(h, _)| h.get(SOURCE_URL_HEADER).cloned())
+        .collect();
+    let truncations: Vec<_> = feeds
+        .iter()
+        .filter_map(|(h, _)| truncation_warning(h, limits.max_items))
         .collect();
-    channel.set_items(new_items);
+    // A merged feed is always RSS, so the upstream Content-Type no longer
+    // applies.
+    let mut format = query.format;
+    let feed = if feeds.len() == 1 {
+        feeds.remove(0).1
+    } else {
+        format = format.or(Some(Format::Rss));
+        merge_feeds(feeds.into_iter().map(|(_, f)| f), query.title.as_deref())
+    };
+
+    let now = query.now();
+    if query.preview {
+        let preview = serde_json::to_string(&Preview::new(&feed, &query, now))
+            .expect("preview serialization cannot fail");
+        return Ok(Response::builder()
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(preview));
+    }
+
+    let next_due = Preview::new(&feed, &query, now).next_due();
+    let key = link.clone();
+    let (feed, drops) = process_feed(feed, &query, link, now);
+
+    let etag = items_etag(&feed);
+    let last_modified = cache.last_modified(&key, &etag, Utc::now());
+    let cache_control = format!("max-age={}", max_age(&query, next_due, now, limits));
+    if is_not_modified(headers, &etag, last_modified) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(http::header::ETAG, etag)
+            .header(http::header::LAST_MODIFIED, http_date(last_modified))
+            .header(http::header::CACHE_CONTROL, cache_control)
+            .body(String::new()));
+    }
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::ETAG, etag)
+        .header(http::header::LAST_MODIFIED, http_date(last_modified))
+        .header(http::header::CACHE_CONTROL, cache_control);
+    builder = with_warnings(builder, &failures, &truncations);
+    builder = with_sources(builder, &sources);
+    builder = with_drops(builder, drops);
+    let (content_type, body) = match format {
+        Some(format) => (
+            HeaderValue::from_static(format.content_type()),
+            render(feed, format, now),
+        ),
+        None => (
+            feed_content_type(h.get(http::header::CONTENT_TYPE), &feed),
+            match feed {
+                Feed::Rss(channel) => channel.to_string(),
+                Feed::Atom(feed) => atom::to_xml(*feed),
+            },
+        ),
+    };
+    let content_type = query.content_type.clone().unwrap_or(content_type);
+    Ok(builder
+        .header(http::header::CONTENT_TYPE, content_type)
+        .body(body))
+}
+
+/// Media types some upstreams mislabel feeds with, which make browsers show
+/// the feed as a page or as plain text.
+const MISLABELED_TYPES: &[&str] = &["text/html", "text/plain", "application/octet-stream"];
+
+/// The Content-Type to serve `feed` with in its own format: `upstream`, the
+/// source feed's, with its charset replaced by UTF-8, or the feed's own type
+/// if `upstream` is missing or mislabels it.
+fn feed_content_type(upstream: Option<&HeaderValue>, feed: &Feed) -> HeaderValue {
+    let mislabeled = |ct: &HeaderValue| {
+        let media_type = ct.to_str().unwrap_or_default().split(';').next();
+        media_type.is_none_or(|t| {
+            let t = t.trim();
+            t.is_empty() || MISLABELED_TYPES.iter().any(|m| t.eq_ignore_ascii_case(m))
+        })
+    };
+    match upstream {
+        Some(ct) if !mislabeled(ct) => utf8_content_type(ct),
+        _ => {
+            let format = match feed {
+                Feed::Rss(_) => Format::Rss,
+                Feed::Atom(_) => Format::Atom,
+            };
+            HeaderValue::from_str(&format!("{}; charset=utf-8", format.content_type()))
+                .expect("feed content types are valid header values")
+        }
+    }
+}
+
+/// Adds a header with the URL each feed was fetched from, after redirects.
+fn with_sources(
+    mut builder: http::response::Builder,
+    sources: &[HeaderValue],
+) -> http::response::Builder {
+    for source in sources {
+        builder = builder.header(SOURCE_URL_HEADER, source);
+    }
+    builder
+}
+
+/// The warning for a feed `load_feed` cut down to `max` items, given the
+/// headers it loaded the feed with, if it did.
+fn truncation_warning(h: &HeaderMap, max: usize) -> Option<String> {
+    let total = h.get(TRUNCATED_HEADER)?.to_str().ok()?;
+    let source = h
+        .get(SOURCE_URL_HEADER)
+        .and_then(|s| s.to_str().ok())
+        .unwrap_or("a feed");
+    Some(format!(
+        "{} has {} items, only the first {} were recast",
+        source, total, max
+    ))
+}
+
+/// Adds a warning header for each feed that failed to load, and each of
+/// `truncations`.
+fn with_warnings(
+    mut builder: http::response::Builder,
+    failures: &[(&String, Error)],
+    truncations: &[String],
+) -> http::response::Builder {
+    let failures = failures
+        .iter()
+        .map(|(url, e)| format!("failed to load {}: {:?}", redact(url), e));
+    for warning in failures.chain(truncations.iter().cloned()) {
+        builder = builder.header(WARNING_HEADER, warning.replace(char::is_control, " "));
+    }
+    builder
+}
+
+/// The JSON body of a `POST /rss` request.
+#[derive(Deserialize, Debug)]
+struct FeedsBody {
+    title: Option<String>,
+    format: Option<String>,
+    feeds: Vec<FeedEntry>,
+}
+
+/// A feed in a `POST /rss` request, with the same options as the query of a
+/// `GET /rss` request.
+#[derive(Deserialize, Debug)]
+struct FeedEntry {
+    url: String,
+    #[serde(flatten)]
+    options: RawQuery,
+}
+
+/// The `POST /rss` route, which merges the feeds listed in a JSON body, each
+/// delayed and filtered by its own options.
+pub(crate) fn post_route(
+    limits: Limits,
+    client: Client,
+    cache: FeedCache,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("rss"))
+        .and(ratelimit::limit(&limits))
+        .and(access::request_id())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::bytes())
+        .and(warp::header::headers_cloned())
+        .and(warp::any().map(move || limits.clone()))
+        .and(warp::any().map(move || client.clone()))
+        .and(warp::any().map(move || cache.clone()))
+        .and_then(post_handler)
+        .recover(handle_error)
+}
+
+async fn post_handler(
+    id: String,
+    body: Bytes,
+    headers: HeaderMap,
+    limits: Limits,
+    client: Client,
+    cache: FeedCache,
+) -> Result<impl Reply, Rejection> {
+    metrics::REQUESTS.inc();
+    let budget = limits.request_timeout;
+    access::traced(
+        id,
+        with_request_timeout(budget, async move {
+            let (queries, title, format) = parse_feeds_body(&body, &limits).map_err(|e| {
+                warn!("failed to parse body: {}", e);
+                warp::reject::custom(Error::QueryParse(e))
+            })?;
+            let user_agent = headers
+                .get(http::header::USER_AGENT)
+                .and_then(|ua| ua.to_str().ok());
+
+            let mut feeds = Vec::new();
+            let mut failures = Vec::new();
+            let mut sources = Vec::new();
+            let mut truncations = Vec::new();
+            let mut drops = Drops::default();
+            // The feeds are merged as of one time, even if they override it.
+            let now = queries
+                .iter()
+                .find_map(|q| q.now_override)
+                .unwrap_or_else(Utc::now);
+            let urls: Vec<_> = queries.iter().map(|q| q.urls[0].clone()).collect();
+            for (query, res) in queries
+                .iter()
+                .zip(load_feeds(&client, &cache, &urls, user_agent, &limits).await)
+            {
+                let (feed_url, res) = autodiscovered(
+                    res,
+                    &query.urls[0],
+                    query.autodiscover,
+                    &client,
+                    &cache,
+                    user_agent,
+                    &limits,
+                )
+                .await;
+                match res {
+                    Ok((h, mut feed)) => {
+                        sources.extend(h.get(SOURCE_URL_HEADER).cloned());
+                        truncations.extend(truncation_warning(&h, limits.max_items));
+                        fill_required(&mut feed, &redact(&feed_url));
+                        if query.absolutize {
+                            absolutize(&mut feed, &redact(&feed_url));
+                        }
+                        let mut channel = match feed {
+                            Feed::Rss(channel) => *channel,
+                            Feed::Atom(feed) => atom::to_channel(&feed),
+                        };
+                        drops += transform_channel(&mut channel, query, now);
+                        feeds.push(Feed::Rss(Box::new(channel)));
+                    }
+                    Err(e) => failures.push((&query.urls[0], e)),
+                }
+            }
+            if feeds.is_empty() {
+                let (_, e) = failures.remove(0);
+                return Err(warp::reject::custom(e));
+            }
+
+            let mut feed = merge_feeds(feeds.into_iter(), title.as_deref());
+            if let Feed::Rss(channel) = &mut feed {
+                order_items(&mut channel.items, Sort::Desc, None, |i| {
+                    item_date(i).map(|(d, _)| d)
+                });
+                channel.set_last_build_date(now.to_rfc2822());
+                update_pub_date(channel, now);
+            }
+            let builder = with_warnings(
+                Response::builder().status(StatusCode::OK),
+                &failures,
+                &truncations,
+            );
+            let builder = with_drops(with_sources(builder, &sources), drops)
+                .header(http::header::CONTENT_TYPE, format.content_type());
+            Ok(builder.body(render(feed, format, now)))
+        }),
+    )
+    .await
+}
+
+/// Parses a `POST /rss` body into a query per feed, plus the title and format
+/// of the merged feed. Options that only apply to a whole response, and
+/// entries listing several feeds, are rejected rather than ignored.
+fn parse_feeds_body(
+    body: &[u8],
+    limits: &Limits,
+) -> Result<(Vec<Query>, Option<String>, Format), String> {
+    let body: FeedsBody =
+        serde_json::from_slice(body).map_err(|e| format!("invalid JSON body: {}", e))?;
+    if body.feeds.is_empty() {
+        return Err("feeds must not be empty".to_string());
+    }
+    let queries = body
+        .feeds
+        .into_iter()
+        .map(|f| {
+            let mut raw = f.options;
+            raw.url = vec![f.url];
+            let query: Query = (raw, limits).try_into()?;
+            if query.urls.len() > 1 {
+                return Err("each feed takes a single url; list the others as feeds".to_string());
+            }
+            for (option, given) in [
+                ("format", query.format.is_some()),
+                ("content_type", query.content_type.is_some()),
+                ("preview", query.preview),
+            ] {
+                if given {
+                    return Err(format!(
+                        "{} applies to the merged feed, not to each feed",
+                        option
+                    ));
+                }
+            }
+            Ok(query)
+        })
+        .collect::<Result<Vec<Query>, String>>()?;
+    let format = body
+        .format
+        .as_deref()
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or(Format::Rss);
+    Ok((queries, body.title, format))
+}
+
+/// The name of the response header counting the items left out of a feed.
+const DROPPED_HEADER: &str = "x-recast-dropped-items";
+/// The name of the response header counting the items left out because they
+/// are not yet due or undated.
+const DROPPED_REASONS_HEADER: &str = "x-recast-dropped-reasons";
 
-    let mut builder = Response::builder().status(StatusCode::OK);
-    if let Some(ct) = h.get(http::header::CONTENT_TYPE) {
-        builder = builder.header(http::header::CONTENT_TYPE, ct);
+/// Adds headers counting the items left out of the feed.
+fn with_drops(builder: http::response::Builder, drops: Drops) -> http::response::Builder {
+    builder.header(DROPPED_HEADER, drops.total).header(
+        DROPPED_REASONS_HEADER,
+        format!(
+            "not_yet_due={}, undated={}",
+            drops.not_yet_due, drops.undated
+        ),
+    )
+}
+
+/// Loads the feeds at `urls` concurrently, like `load_feed`. When there are
+/// several, each waits for one of `Limits::fetch_permits` first.
+pub(crate) async fn load_feeds(
+    client: &Client,
+    cache: &FeedCache,
+    urls: &[String],
+    user_agent: Option<&str>,
+    limits: &Limits,
+) -> Vec<Result<(HeaderMap, Feed), Error>> {
+    if let [url] = urls {
+        return vec![load_feed(client, cache, url, user_agent, limits).await];
     }
-    Ok(builder.body(channel.to_string()))
+    join_all(urls.iter().map(|u| async move {
+        let _permit = limits
+            .fetch_permits
+            .acquire()
+            .await
+            .expect("fetch permits are never closed");
+        load_feed(client, cache, u, user_agent, limits).await
+    }))
+    .await
 }
 
-fn update_title(channel: &mut Channel, delay: chrono::Duration) {
-    warn!("{}", delay.num_hours());
-    let ht = HumanTime::from(delay).to_text_en(Accuracy::Precise, Tense::Present);
-    channel.set_title(format!("{} (Rerun after {})", channel.title(), ht));
+/// Fetches and parses the feed at `url`, reusing a cached copy if there is
+/// one. Requests for a feed that is not cached while it is being fetched
+/// share that fetch, with the User-Agent of the request that started it.
+pub(crate) async fn load_feed(
+    client: &Client,
+    cache: &FeedCache,
+    url: &str,
+    user_agent: Option<&str>,
+    limits: &Limits,
+) -> Result<(HeaderMap, Feed), Error> {
+    // Local files are trusted, so are not checked as URLs from the network
+    // are.
+    if !is_file_url(url) {
+        check_host(url, &limits.allowed_hosts)?;
+    }
+    if let Some(entry) = cache.get(url).await {
+        let feed = entry.feed();
+        return Ok((entry.headers, feed));
+    }
+
+    let fetch = {
+        let (client, cache, url) = (client.clone(), cache.clone(), url.to_string());
+        let (user_agent, limits) = (user_agent.map(str::to_string), limits.clone());
+        async move { fetch_and_parse(&client, &cache, &url, user_agent.as_deref(), &limits).await }
+    };
+    let (h, feed) = cache.single_flight(url, fetch).await?;
+    Ok((h, Feed::clone(&feed)))
 }
 
-fn update_link(channel: &mut Channel, host: &str, path: &str, query: &str) {
+/// Fetches and parses the feed at `url` for `load_feed`, revalidating the
+/// expired copy in `cache` if there is one, and caches it.
+async fn fetch_and_parse(
+    client: &Client,
+    cache: &FeedCache,
+    url: &str,
+    user_agent: Option<&str>,
+    limits: &Limits,
+) -> Result<(HeaderMap, Arc<Feed>), Error> {
+    let is_file = is_file_url(url);
+    let stale = cache.get_stale(url).await;
+    let fetched = if is_file {
+        read_file(url, limits).await?
+    } else {
+        check_target(url, &limits.allowed_cidrs).await?;
+        let conditional = stale.as_ref().map(Entry::validators).unwrap_or_default();
+        fetch_feed(client, url, user_agent, &conditional, limits).await?
+    };
+    let (h, content) = match fetched {
+        Fetched::Feed(h, content) => (h, content),
+        Fetched::NotModified => {
+            // Only a request with validators can be answered with 304, and
+            // those come from a cached entry.
+            let entry = stale.expect("304 without a cached feed");
+            cache.refresh(url, entry.clone()).await;
+            return Ok(entry.into_parts());
+        }
+    };
+    // Parsing is CPU-bound and a pathological feed may take long, so it runs
+    // off the async workers, where it cannot keep the request timeout from
+    // firing.
+    let headers = h.clone();
+    let mut feed = tokio::task::spawn_blocking(move || parse_feed(&content, &headers))
+        .await
+        .expect("parsing a feed panicked")
+        .map_err(|e| {
+            warn!("failed to parse feed {}: {:?}", redact(url), e);
+            e
+        })?;
+    let mut h = h;
+    if let Some(total) = truncate_items(&mut feed, limits.max_items) {
+        warn!(
+            "feed {} has {} items, recasting the first {}",
+            redact(url),
+            total,
+            limits.max_items
+        );
+        h.insert(TRUNCATED_HEADER, HeaderValue::from(total));
+    }
+    // Only feeds that parse are cached, so a broken feed is refetched. The
+    // cached copy is parsed once and cloned for each request, which is far
+    // cheaper than parsing it again.
+    let feed = Arc::new(feed);
+    cache.insert(url, h.clone(), feed.clone()).await;
+    Ok((h, feed))
+}
+
+/// Drops the items of `feed` beyond the first `max`, so a feed of countless
+/// tiny items cannot tie up the server. Returns how many items it had if
+/// there were more.
+fn truncate_items(feed: &mut Feed, max: usize) -> Option<usize> {
+    let total = match feed {
+        Feed::Rss(channel) => channel.items.len(),
+        Feed::Atom(feed) => feed.entries.len(),
+    };
+    if total <= max {
+        return None;
+    }
+    match feed {
+        Feed::Rss(channel) => channel.items.truncate(max),
+        Feed::Atom(feed) => feed.entries.truncate(max),
+    }
+    Some(total)
+}
+
+/// Replaces `res`, the result of loading `url`, with the feed the page at
+/// `url` links to if `autodiscover` is set and `url` turned out to be an HTML
+/// page with a feed link. Returns the URL the feed was loaded from along with
+/// the result. Only one link is followed, so pages linking to other pages
+/// cannot send recast round in circles.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn autodiscovered(
+    res: Result<(HeaderMap, Feed), Error>,
+    url: &str,
+    autodiscover: bool,
+    client: &Client,
+    cache: &FeedCache,
+    user_agent: Option<&str>,
+    limits: &Limits,
+) -> (String, Result<(HeaderMap, Feed), Error>) {
+    let link = match res {
+        Err(Error::NotAFeed(Some(link))) if autodiscover => link,
+        res => return (url.to_string(), res),
+    };
+    let feed_url = match Url::parse(url).and_then(|page| page.join(&link)) {
+        Ok(feed_url) if matches!(feed_url.scheme(), "http" | "https") => String::from(feed_url),
+        _ => return (url.to_string(), Err(Error::NotAFeed(None))),
+    };
+    info!(
+        "{} is a web page, loading the feed it links to, {}",
+        redact(url),
+        redact(&feed_url)
+    );
+    let res = load_feed(client, cache, &feed_url, user_agent, limits)
+        .await
+        .map_err(|e| match e {
+            Error::NotAFeed(_) => Error::NotAFeed(None),
+            e => e,
+        });
+    (feed_url, res)
+}
+
+/// The URL of the request for `path` and `query` on `host`, which recast
+/// feeds link to as their own. Credentials in `query` are left out, since
+/// the feed may be read by others.
+pub(crate) fn recast_link(host: &str, path: &str, query: &str) -> String {
     let scheme: &'static str = if cfg!(debug_assertions) {
         "http"
     } else {
         "https"
     };
-    let link = format!("{scheme}://{host}/{path}?{query}");
-    channel.set_link(link);
+    let path = path.trim_start_matches('/');
+    let query = redact_query(query);
+    format!("{scheme}://{host}/{path}?{query}")
 }
 
-fn postdate_item(item: &mut Item, delay: Duration) -> Option<Item> {
-    let orig_pubdate = item
-        .pub_date()
-        .and_then(|d| DateTime::parse_from_rfc2822(&d).ok())?;
-    let new_pubdate = compare_time_after_delay(orig_pubdate, delay, chrono::Utc::now())?;
-    item.set_pub_date(new_pubdate.to_rfc2822());
-
-    if let Some(orig_desc) = item.description() {
-        let new_desc = format!("(originally published on {}) {}", orig_pubdate, orig_desc);
-        item.set_description(new_desc);
-    }
+impl warp::reject::Reject for Error {}
 
-    Some(item.to_owned())
+/// The JSON body of an error response.
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    message: String,
 }
 
-fn compare_time_after_delay<T: TimeZone, U: TimeZone>(
-    t: DateTime<T>,
-    delay: Duration,
-    now: DateTime<U>,
-) -> Option<DateTime<T>> {
-    t.checked_add_signed(delay)
-        .and_then(|new_t| if new_t < now { Some(new_t) } else { None })
-}
+/// Replies to recast's own errors; other rejections, such as for requests
+/// to other routes, are passed on.
+pub(crate) async fn handle_error(err: Rejection) -> Result<impl Reply, Rejection> {
+    let e = match err.find::<Error>() {
+        Some(e) => e,
+        None => return Err(err),
+    };
+    let error = e.code();
+    metrics::ERRORS.with_label_values(&[error]).inc();
+    let (code, message) = match e {
+        Error::FeedLoad(r) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to load feed: {}", r),
+        ),
+        Error::FeedTimeout(secs) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            format!("failed to load feed: timed out after {} seconds", secs),
+        ),
+        Error::UpstreamDns(r) => (
+            StatusCode::BAD_GATEWAY,
+            format!("failed to load feed: DNS lookup failed: {}", r),
+        ),
+        Error::UpstreamConnect(r) => (
+            StatusCode::BAD_GATEWAY,
+            format!("failed to load feed: connection failed: {}", r),
+        ),
+        Error::UpstreamStatus(s) => (
+            if s.is_client_error() {
+                *s
+            } else {
+                StatusCode::BAD_GATEWAY
+            },
+            format!("failed to load feed: upstream returned {}", s),
+        ),
+        Error::FeedTooLarge(max) => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("failed to load feed: larger than {} bytes", max),
+        ),
+        Error::Forbidden(r) => (StatusCode::FORBIDDEN, format!("forbidden: {}", r)),
+        Error::HostNotAllowed(h) => (
+            StatusCode::FORBIDDEN,
+            format!("forbidden: host {} is not allowed", h),
+        ),
+        Error::FeedParse(r) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to parse feed: {}", r),
+        ),
+        Error::QueryParse(r) => (
+            StatusCode::BAD_REQUEST,
+            format!("failed to parse query: {}", r),
+        ),
+        Error::OpmlParse(r) => (
+            StatusCode::BAD_REQUEST,
+            format!("failed to parse OPML: {}", r),
+        ),
+        Error::RateLimited(secs) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("too many requests, retry after {} seconds", secs),
+        ),
+        Error::TooManyRedirects(max) => (
+            StatusCode::BAD_GATEWAY,
+            format!("failed to load feed: redirected more than {} times", max),
+        ),
+        Error::NotAFeed(_) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "URL did not return a feed".to_string(),
+        ),
+        Error::RequestTimeout(secs) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("request timed out after {} seconds", secs),
+        ),
+        Error::UpstreamRateLimited(_) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            "failed to load feed: upstream is rate limiting recast".to_string(),
+        ),
+    };
 
-#[derive(Debug)]
-enum Error {
-    FeedLoad(String),
-    FeedParse(String),
-    QueryParse(String),
+    let mut res = warp::reply::with_status(warp::reply::json(&ErrorBody { error, message }), code)
+        .into_response();
+    let retry_after = match e {
+        Error::RateLimited(secs) => Some(HeaderValue::from(*secs)),
+        Error::UpstreamRateLimited(retry_after) => retry_after.clone(),
+        _ => None,
+    };
+    if let Some(retry_after) = retry_after {
+        res.headers_mut()
+            .insert(http::header::RETRY_AFTER, retry_after);
+    }
+    Ok(res)
 }
 
-impl warp::reject::Reject for Error {}
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use chrono::DateTime;
+    use rss::Channel;
+    use tokio::sync::Semaphore;
+    use warp::Filter;
+
+    use super::*;
+    use crate::{
+        fetch::{build_client, FORWARDED_USER_AGENT_HEADER, USER_AGENT},
+        transform::tests::{raw_query, FEED},
+    };
+
+    /// Serves `FEED` on a local port, recording the headers of every request.
+    fn serve_feed() -> (SocketAddr, Arc<Mutex<Vec<HeaderMap>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let route = warp::header::headers_cloned().map(move |h: HeaderMap| {
+            recorded.lock().unwrap().push(h);
+            FEED
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, requests)
+    }
+
+    /// Serves `FEED` with an ETag, answering `304 Not Modified` to any
+    /// request with `If-None-Match`.
+    fn serve_etag_feed() -> (SocketAddr, Arc<Mutex<Vec<HeaderMap>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let route = warp::header::headers_cloned().map(move |h: HeaderMap| {
+            let revalidating = h.contains_key(http::header::IF_NONE_MATCH);
+            recorded.lock().unwrap().push(h);
+            if revalidating {
+                warp::reply::with_status("", StatusCode::NOT_MODIFIED).into_response()
+            } else {
+                warp::reply::with_header(FEED, http::header::ETAG, "\"v1\"").into_response()
+            }
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn handler_400_empty_url() {
+        for path in [
+            "/rss?url=&delay=2",
+            "/rss?url=https%3A%2F%2Fa.example%2Frss.xml,&delay=2",
+        ] {
+            let res = warp::test::request()
+                .path(path)
+                .header("host", "example.com")
+                .reply(&test_route(Limits::default()))
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", path);
+            let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(
+                body["message"], "failed to parse query: url must not be empty",
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn load_feed_sets_user_agent() {
+        let (addr, requests) = serve_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let client = build_client(&limits).unwrap();
+
+        load_feed(
+            &client,
+            &FeedCache::new(limits.cache_ttl),
+            &format!("http://{}/rss.xml", addr),
+            Some("reader/1.0"),
+            &limits,
+        )
+        .await
+        .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0][http::header::USER_AGENT], USER_AGENT);
+        assert_eq!(requests[0][FORWARDED_USER_AGENT_HEADER], "reader/1.0");
+    }
+
+    #[tokio::test]
+    async fn load_feed_shares_concurrent_fetches() {
+        let requests = Arc::new(Mutex::new(0));
+        let recorded = requests.clone();
+        let route = warp::any().and_then(move || {
+            *recorded.lock().unwrap() += 1;
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                Ok::<_, std::convert::Infallible>(FEED)
+            }
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let client = build_client(&limits).unwrap();
+        // Even without caching, concurrent requests fetch the feed once.
+        let cache = FeedCache::new(std::time::Duration::ZERO);
+        let url = format!("http://{}/rss.xml", addr);
+
+        let results =
+            join_all((0..5).map(|_| load_feed(&client, &cache, &url, None, &limits))).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(*requests.lock().unwrap(), 1);
+
+        // Once it is done, the next request fetches it again.
+        load_feed(&client, &cache, &url, None, &limits)
+            .await
+            .unwrap();
+        assert_eq!(*requests.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn load_feed_reuses_cached_feeds() {
+        let (addr, requests) = serve_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let client = build_client(&limits).unwrap();
+        let cache = FeedCache::new(limits.cache_ttl);
+        let url = format!("http://{}/rss.xml", addr);
+
+        let query: Query = (raw_query("1d"), &limits).try_into().unwrap();
+        for _ in 0..2 {
+            let (_, feed) = load_feed(&client, &cache, &url, None, &limits)
+                .await
+                .unwrap();
+            // Transforming one request's copy leaves the cached feed intact.
+            let channel = match feed {
+                Feed::Rss(channel) => channel,
+                Feed::Atom(_) => panic!("expected an RSS feed"),
+            };
+            assert_eq!(channel.items()[0].description(), Some("hi"));
+            process_feed(Feed::Rss(channel), &query, url.clone(), Utc::now());
+        }
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn load_feeds_limits_concurrency() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (counter, peak) = (in_flight.clone(), most.clone());
+        let route = warp::any().and_then(move || {
+            let (counter, peak) = (counter.clone(), peak.clone());
+            async move {
+                let now = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                counter.fetch_sub(1, Ordering::SeqCst);
+                Ok::<_, std::convert::Infallible>(FEED)
+            }
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            fetch_permits: Arc::new(Semaphore::new(2)),
+            ..Limits::default()
+        };
+        let client = build_client(&limits).unwrap();
+        let urls: Vec<_> = (0..6)
+            .map(|i| format!("http://{}/{}.xml", addr, i))
+            .collect();
+
+        let feeds = load_feeds(
+            &client,
+            &FeedCache::new(limits.cache_ttl),
+            &urls,
+            None,
+            &limits,
+        )
+        .await;
+
+        assert!(feeds.iter().all(Result::is_ok));
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        assert_eq!(limits.fetch_permits.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn load_feed_revalidates_expired_feeds() {
+        let (addr, requests) = serve_etag_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let client = build_client(&limits).unwrap();
+        // Expires immediately, so every load revalidates.
+        let cache = FeedCache::new(std::time::Duration::from_nanos(1));
+        let url = format!("http://{}/rss.xml", addr);
+
+        for _ in 0..2 {
+            let (_, feed) = load_feed(&client, &cache, &url, None, &limits)
+                .await
+                .unwrap();
+            assert!(matches!(feed, Feed::Rss(c) if c.items().len() == 1));
+        }
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1][http::header::IF_NONE_MATCH], "\"v1\"");
+    }
+
+    #[tokio::test]
+    async fn handle_error_replies_with_json() {
+        let rejection = warp::reject::custom(Error::QueryParse("bad delay".to_string()));
+        let res = handle_error(rejection).await.unwrap().into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            res.headers()[http::header::CONTENT_TYPE],
+            "application/json"
+        );
+
+        let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "query_parse",
+                "message": "failed to parse query: bad delay",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn post_merges_feeds_with_their_own_delays() {
+        let (addr, _) = serve_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let client = build_client(&limits).unwrap();
+        let route = post_route(limits, client, FeedCache::new(std::time::Duration::ZERO));
+        let url = format!("http://{}/rss.xml", addr);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/rss")
+            .json(&serde_json::json!({
+                "title": "Both",
+                "feeds": [
+                    { "url": url, "delay": "1h" },
+                    { "url": url, "delay": "1w", "annotate": false },
+                ],
+            }))
+            .reply(&route)
+            .await;
+
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        let channel = Channel::read_from(&res.body()[..]).unwrap();
+        assert_eq!(channel.title(), "Both");
+        let dates: Vec<_> = channel
+            .items()
+            .iter()
+            .filter_map(|i| i.pub_date())
+            .collect();
+        assert_eq!(
+            dates,
+            [
+                "Thu, 09 Jan 2020 03:04:05 +0000",
+                "Thu, 02 Jan 2020 04:04:05 +0000"
+            ]
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/rss")
+            .json(&serde_json::json!({ "feeds": [{ "url": url, "delay": "1m" }] }))
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn post_rejects_options_it_would_ignore() {
+        let limits = Limits::default();
+        let client = build_client(&limits).unwrap();
+        let route = post_route(limits, client, FeedCache::new(std::time::Duration::ZERO));
+        let url = "https://example.com/a.xml";
+
+        for (entry, message) in [
+            (
+                serde_json::json!({ "url": format!("{},https://example.com/b.xml", url) }),
+                "each feed takes a single url",
+            ),
+            (
+                serde_json::json!({ "url": url, "format": "atom" }),
+                "format applies to the merged feed",
+            ),
+            (
+                serde_json::json!({ "url": url, "content_type": "text/xml" }),
+                "content_type applies to the merged feed",
+            ),
+            (
+                serde_json::json!({ "url": url, "preview": true }),
+                "preview applies to the merged feed",
+            ),
+        ] {
+            let res = warp::test::request()
+                .method("POST")
+                .path("/rss")
+                .json(&serde_json::json!({ "feeds": [entry, { "url": url }] }))
+                .reply(&route)
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", message);
+            let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(body["error"], "query_parse");
+            assert!(
+                body["message"].as_str().unwrap().contains(message),
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_transforms_copies_of_the_cached_feed() {
+        let (addr, requests) = serve_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let client = build_client(&limits).unwrap();
+        let cache = FeedCache::new(limits.cache_ttl);
+        let route = route(limits, client, cache.clone());
+        let url = format!("http://{}/rss.xml", addr);
+        let get = |extra: &str| {
+            warp::test::request()
+                .path(&format!("/rss?url={}&{}", urlencoding::encode(&url), extra))
+                .header("host", "example.com")
+                .reply(&route)
+        };
+
+        let first = get("delay=1w&title=Changed&annotate=description").await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let second = get("delay=1h").await;
+        assert_eq!(second.status(), StatusCode::OK);
+        // The second request is served from the cached feed, untouched by
+        // the first.
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        let channel = Channel::read_from(&second.body()[..]).unwrap();
+        assert_eq!(channel.title(), "Example (Rerun after 1 hour)");
+        let item = &channel.items()[0];
+        assert_eq!(item.pub_date(), Some("Thu, 02 Jan 2020 04:04:05 +0000"));
+        assert_eq!(item.description(), Some("hi"));
+
+        let cached = match cache.get(&url).await.unwrap().feed() {
+            Feed::Rss(channel) => channel,
+            Feed::Atom(_) => panic!("expected an RSS feed"),
+        };
+        assert_eq!(cached.title(), "Example");
+        assert_eq!(
+            cached.items()[0].pub_date(),
+            Some("Thu, 02 Jan 2020 03:04:05 +0000")
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_caches_spellings_of_a_url_as_one() {
+        let (addr, requests) = serve_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let route = test_route(limits);
+        for url in [
+            format!("http://{}/rss.xml", addr),
+            format!("HTTP://{}/rss.xml", addr),
+            format!("http://{}/rss.xml/", addr),
+        ] {
+            let res = warp::test::request()
+                .path(&format!("/rss?url={}&delay=1h", urlencoding::encode(&url)))
+                .header("host", "example.com")
+                .reply(&route)
+                .await;
+            assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        }
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn max_age_until_next_item_is_due() {
+        let limits = Limits::default();
+        let now = Utc::now();
+        let query: Query = (raw_query("1d"), &limits).try_into().unwrap();
+        assert_eq!(max_age(&query, None, now, &limits), 3600);
+        let due = (now + chrono::Duration::minutes(10)).into();
+        assert_eq!(max_age(&query, Some(due), now, &limits), 600);
+        let past = (now - chrono::Duration::minutes(10)).into();
+        assert_eq!(max_age(&query, Some(past), now, &limits), 0);
+
+        let raw: RawQuery = "url=http%3A%2F%2Fa.example&delay=1d&clamp=true"
+            .parse()
+            .unwrap();
+        let query: Query = (raw, &limits).try_into().unwrap();
+        assert_eq!(max_age(&query, None, now, &limits), 300);
+    }
+
+    #[tokio::test]
+    async fn handler_reads_files_when_allowed() {
+        let path = std::env::temp_dir().join(format!("recast-{}.xml", std::process::id()));
+        std::fs::write(&path, FEED).unwrap();
+        let url = Url::from_file_path(&path).unwrap().to_string();
+        let request = || {
+            warp::test::request()
+                .path(&format!("/rss?url={}&delay=1h", urlencoding::encode(&url)))
+                .header("host", "example.com")
+        };
+
+        let res = request().reply(&test_route(Limits::default())).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let limits = Limits {
+            allow_file: true,
+            ..Limits::default()
+        };
+        let res = request().reply(&test_route(limits)).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        assert_eq!(res.headers()[SOURCE_URL_HEADER], url.as_str());
+        let channel = Channel::read_from(&res.body()[..]).unwrap();
+        assert_eq!(channel.items().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn handler_fills_in_missing_channel_fields() {
+        let route = warp::any().map(|| {
+            r#"<rss version="2.0"><channel>
+<item><title>Old</title><guid>a</guid><pubDate>Thu, 02 Jan 2020 03:04:05 +0000</pubDate></item>
+</channel></rss>"#
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let url = format!("http://{}/rss.xml", addr);
+
+        let res = warp::test::request()
+            .path(&format!("/rss?url={}&delay=1h", urlencoding::encode(&url)))
+            .header("host", "example.com")
+            .reply(&test_route(limits))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        let channel = Channel::read_from(&res.body()[..]).unwrap();
+        assert_eq!(channel.title(), "127.0.0.1 (Rerun after 1 hour)");
+        assert_eq!(channel.description(), "127.0.0.1");
+        assert!(channel.link().starts_with("http://example.com/rss?"));
+        assert_eq!(channel.items().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn handler_merges_feeds_with_a_delay_each() {
+        let route = warp::path!(String)
+            .map(|name: String| FEED.replace("<guid>a", &format!("<guid>{}", name)));
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let route = test_route(limits);
+        let urls =
+            ["a", "b"].map(|n| urlencoding::encode(&format!("http://{}/{}", addr, n)).into_owned());
+
+        let res = warp::test::request()
+            .path(&format!(
+                "/rss?url={}&url={}&delay=1h&delay=1w",
+                urls[0], urls[1]
+            ))
+            .header("host", "example.com")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        let channel = Channel::read_from(&res.body()[..]).unwrap();
+        let dates: Vec<_> = channel
+            .items()
+            .iter()
+            .map(|i| (i.guid().unwrap().value(), i.pub_date().unwrap()))
+            .collect();
+        assert_eq!(
+            dates,
+            [
+                ("b", "Thu, 09 Jan 2020 03:04:05 +0000"),
+                ("a", "Thu, 02 Jan 2020 04:04:05 +0000")
+            ]
+        );
+
+        let res = warp::test::request()
+            .path(&format!(
+                "/rss?url={}&url={}&delay=1h&delay=1w&delay=1d",
+                urls[0], urls[1]
+            ))
+            .header("host", "example.com")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn handler_merges_feeds_with_one_delay_for_all() {
+        let route = warp::path!(String)
+            .map(|name: String| FEED.replace("<guid>a", &format!("<guid>{}", name)));
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let route = test_route(limits);
+        let urls =
+            ["a", "b"].map(|n| urlencoding::encode(&format!("http://{}/{}", addr, n)).into_owned());
+
+        let res = warp::test::request()
+            .path(&format!("/rss?url={}&url={}&delay=1d", urls[0], urls[1]))
+            .header("host", "example.com")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        let channel = Channel::read_from(&res.body()[..]).unwrap();
+        let dates: Vec<_> = channel
+            .items()
+            .iter()
+            .map(|i| (i.guid().unwrap().value(), i.pub_date().unwrap()))
+            .collect();
+        assert_eq!(
+            dates,
+            [
+                ("a", "Fri, 03 Jan 2020 03:04:05 +0000"),
+                ("b", "Fri, 03 Jan 2020 03:04:05 +0000")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_merges_feeds_sharing_a_guid_with_a_delay_each() {
+        let (addr, _) = serve_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let route = test_route(limits);
+        let urls =
+            ["a", "b"].map(|n| urlencoding::encode(&format!("http://{}/{}", addr, n)).into_owned());
+
+        let res = warp::test::request()
+            .path(&format!(
+                "/rss?url={}&url={}&delay=1h&delay=1w",
+                urls[0], urls[1]
+            ))
+            .header("host", "example.com")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        let body = std::str::from_utf8(res.body()).unwrap();
+        assert!(!body.contains("feedDelay"), "{}", body);
+        let channel = Channel::read_from(body.as_bytes()).unwrap();
+        let dates: Vec<_> = channel
+            .items()
+            .iter()
+            .map(|i| (i.guid().unwrap().value(), i.pub_date().unwrap()))
+            .collect();
+        assert_eq!(
+            dates,
+            [
+                ("a", "Thu, 09 Jan 2020 03:04:05 +0000"),
+                ("a", "Thu, 02 Jan 2020 04:04:05 +0000")
+            ]
+        );
+    }
+
+    fn test_route(
+        limits: Limits,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let client = build_client(&limits).unwrap();
+        let cache = FeedCache::new(limits.cache_ttl);
+        route(limits, client, cache)
+    }
+
+    #[tokio::test]
+    async fn handler_200() {
+        let (addr, _) = serve_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let url = format!("http://{}/rss.xml", addr);
+
+        let res = warp::test::request()
+            .path(&format!("/rss?url={}&delay=1", urlencoding::encode(&url)))
+            .header("host", "example.com")
+            .reply(&test_route(limits))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        assert_eq!(res.headers()[SOURCE_URL_HEADER], url.as_str());
+        assert_eq!(res.headers()[DROPPED_HEADER], "0");
+        assert_eq!(
+            res.headers()[DROPPED_REASONS_HEADER],
+            "not_yet_due=0, undated=0"
+        );
+        assert_eq!(res.headers()[http::header::CACHE_CONTROL], "max-age=3600");
+        // The mock serves the feed as text/plain, which is replaced.
+        assert_eq!(
+            res.headers()[http::header::CONTENT_TYPE],
+            "application/rss+xml; charset=utf-8"
+        );
+        let channel = Channel::read_from(&res.body()[..]).unwrap();
+        assert_eq!(channel.items().len(), 1);
+        assert_eq!(
+            channel.link(),
+            format!(
+                "http://example.com/rss?url={}&delay=1",
+                urlencoding::encode(&url)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_truncates_huge_feeds() {
+        let items: String = (1..=3)
+            .map(|d| {
+                format!(
+                    "<item><guid>{}</guid><pubDate>0{} Jan 2020 00:00:00 +0000</pubDate></item>",
+                    d, d
+                )
+            })
+            .collect();
+        let feed = format!(
+            "<rss version=\"2.0\"><channel><title>T</title><link>http://example.com</link><description>d</description>{}</channel></rss>",
+            items
+        );
+        let (addr, server) =
+            warp::serve(warp::any().map(move || feed.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            max_items: 2,
+            ..Limits::default()
+        };
+        let url = format!("http://{}/rss.xml", addr);
+
+        let res = warp::test::request()
+            .path(&format!("/rss?url={}&delay=1", urlencoding::encode(&url)))
+            .header("host", "example.com")
+            .reply(&test_route(limits))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[WARNING_HEADER],
+            format!("{} has 3 items, only the first 2 were recast", url).as_str()
+        );
+        let channel = Channel::read_from(&res.body()[..]).unwrap();
+        let guids: Vec<_> = channel
+            .items()
+            .iter()
+            .filter_map(|i| i.guid().map(|g| g.value()))
+            .collect();
+        assert_eq!(guids, ["2", "1"]);
+    }
+
+    #[tokio::test]
+    async fn handler_renders_requested_format() {
+        let (addr, _) = serve_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let route = test_route(limits);
+        let url = urlencoding::encode(&format!("http://{}/rss.xml", addr)).into_owned();
+        let get = |format: &str| {
+            warp::test::request()
+                .path(&format!("/rss?url={}&delay=1&format={}", url, format))
+                .header("host", "example.com")
+                .reply(&route)
+        };
 
-pub(crate) async fn handle_error(err: Rejection) -> Result<impl Reply, Infallible> {
-    let code;
-    let message;
+        let res = get("atom").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[http::header::CONTENT_TYPE],
+            "application/atom+xml"
+        );
+        let feed: atom_syndication::Feed =
+            std::str::from_utf8(res.body()).unwrap().parse().unwrap();
+        assert_eq!(feed.entries[0].id, "a");
+
+        let res = get("json").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[http::header::CONTENT_TYPE],
+            "application/feed+json"
+        );
+        let feed: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(feed["items"][0]["id"], "a");
+
+        let res = get("yaml").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "query_parse");
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .contains("unknown format 'yaml'"));
+    }
 
-    if let Some(e) = err.find::<Error>() {
-        (code, message) = match e {
-            Error::FeedLoad(r) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("failed to load feed: {}", r),
+    #[tokio::test]
+    async fn handler_overrides_content_type() {
+        let (addr, _) = serve_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let url = urlencoding::encode(&format!("http://{}/rss.xml", addr)).into_owned();
+        for (extra, status, content_type) in [
+            (
+                "&content_type=application/xml",
+                StatusCode::OK,
+                "application/xml",
             ),
-            Error::FeedParse(r) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("failed to parse feed: {}", r),
+            (
+                "&format=atom&content_type=text/xml%3B%20charset%3Dutf-8",
+                StatusCode::OK,
+                "text/xml; charset=utf-8",
             ),
-            Error::QueryParse(r) => (
+            (
+                "&content_type=xml",
                 StatusCode::BAD_REQUEST,
-                format!("failed to parse query: {}", r),
+                "application/json",
+            ),
+        ] {
+            let res = warp::test::request()
+                .path(&format!("/rss?url={}&delay=1{}", url, extra))
+                .header("host", "example.com")
+                .reply(&test_route(limits.clone()))
+                .await;
+            assert_eq!(res.status(), status, "{}", extra);
+            assert_eq!(
+                res.headers()[http::header::CONTENT_TYPE],
+                content_type,
+                "{}",
+                extra
+            );
+        }
+    }
+
+    #[test]
+    fn feed_content_type_replaces_mislabels() {
+        let rss = Feed::Rss(Box::default());
+        let atom = Feed::Atom(Box::default());
+        for (upstream, feed, expected) in [
+            (
+                Some("application/rss+xml; charset=ISO-8859-1"),
+                &rss,
+                "application/rss+xml; charset=utf-8",
+            ),
+            (Some("text/xml"), &rss, "text/xml"),
+            (
+                Some("text/HTML; charset=utf-8"),
+                &rss,
+                "application/rss+xml; charset=utf-8",
             ),
+            (
+                Some("text/plain"),
+                &atom,
+                "application/atom+xml; charset=utf-8",
+            ),
+            (
+                Some("application/octet-stream"),
+                &rss,
+                "application/rss+xml; charset=utf-8",
+            ),
+            (None, &atom, "application/atom+xml; charset=utf-8"),
+        ] {
+            let upstream = upstream.map(HeaderValue::from_static);
+            assert_eq!(
+                feed_content_type(upstream.as_ref(), feed),
+                expected,
+                "{:?}",
+                upstream
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_authenticates_upstream() {
+        // Serves `FEED` only to requests authenticated as `user:s3cret`.
+        let route = warp::header::optional("authorization").map(|auth: Option<String>| {
+            if auth.as_deref() == Some("Basic dXNlcjpzM2NyZXQ=") {
+                warp::reply::with_status(FEED, StatusCode::OK)
+            } else {
+                warp::reply::with_status("", StatusCode::UNAUTHORIZED)
+            }
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
         };
-    } else {
-        (code, message) = (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "unknown error".to_string(),
-        );
-    }
-
-    Ok(warp::reply::with_status(message, code))
-}
-
-// #[cfg(test)]
-// mod tests {
-//     use crate::{
-//         rss::{Query, RawQuery},
-//         server,
-//     };
-//
-//     #[async_std::test]
-//     async fn try_from_raw_query() {
-//         let query = RawQuery {
-//             url: "https%3A%2F%2Fexample.com%2Frss.xml".to_string(),
-//             delay: "1".to_string(),
-//         };
-//
-//         let query: Query = query.try_into().unwrap();
-//         assert_eq!(query.url, "https://example.com/rss.xml");
-//         assert_eq!(query.delay, Query::min_delay());
-//     }
-//
-//     #[async_std::test]
-//     async fn handler_200() -> tide::Result<()> {
-//         let url = "http://example.com/rss?url=https%3A%2F%2Fvideo%2Dapi%2Ewsj%2Ecom%2Fpodcast%2Frss%2Fwsj%2Ftech%2Dnews%2Dbriefing&delay=1";
-//         let app = server();
-//         let res = surf::Client::with_http_client(app).get(url).await?;
-//         assert_eq!(res.status(), tide::StatusCode::Ok, "{:?}", res);
-//         Ok(())
-//     }
-//
-//     #[async_std::test]
-//     async fn handler_400_invalid_query() -> tide::Result<()> {
-//         let app = server();
-//         let res = surf::Client::with_http_client(app)
-//             .get("http://example.com/rss")
-//             .await?;
-//         assert_eq!(res.status(), tide::StatusCode::BadRequest);
-//         Ok(())
-//     }
-// }
-//
+
+        let embedded =
+            urlencoding::encode(&format!("http://user:s3cret@{}/rss.xml", addr)).into_owned();
+        let bare = urlencoding::encode(&format!("http://{}/rss.xml", addr)).into_owned();
+        for (query, status) in [
+            (format!("url={}", embedded), StatusCode::OK),
+            (
+                format!("url={}&auth_user=user&auth_pass=s3cret", bare),
+                StatusCode::OK,
+            ),
+            (format!("url={}", bare), StatusCode::UNAUTHORIZED),
+            (
+                format!("url={}&auth_user=user&auth_pass=wrong", bare),
+                StatusCode::UNAUTHORIZED,
+            ),
+        ] {
+            let res = warp::test::request()
+                .path(&format!("/rss?{}&delay=1&rewrite_self=true", query))
+                .header("host", "example.com")
+                .reply(&test_route(limits.clone()))
+                .await;
+            assert_eq!(res.status(), status, "{}", query);
+            // Neither the channel's link nor its self link, which point at
+            // recast, give the password away.
+            let body = String::from_utf8_lossy(res.body());
+            assert!(!body.contains("s3cret"), "{}", body);
+            if status == StatusCode::UNAUTHORIZED {
+                let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+                assert_eq!(body["error"], "upstream_status", "{}", query);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_times_out_slow_requests() {
+        let route = warp::any().and_then(|| async {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            Ok::<_, Rejection>(FEED)
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            request_timeout: std::time::Duration::from_millis(100),
+            ..Limits::default()
+        };
+        let url = format!("http://{}/rss.xml", addr);
+
+        let res = warp::test::request()
+            .path(&format!("/rss?url={}&delay=1", urlencoding::encode(&url)))
+            .header("host", "example.com")
+            .reply(&test_route(limits))
+            .await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "request_timeout");
+    }
+
+    #[tokio::test]
+    async fn handler_times_out_slow_fetches() {
+        let route = warp::any().and_then(|| async {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            Ok::<_, Rejection>(FEED)
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            fetch_timeout: std::time::Duration::from_millis(100),
+            ..Limits::default()
+        };
+        let url = format!("http://{}/rss.xml", addr);
+
+        let res = warp::test::request()
+            .path(&format!("/rss?url={}&delay=1", urlencoding::encode(&url)))
+            .header("host", "example.com")
+            .reply(&test_route(limits))
+            .await;
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "feed_timeout");
+    }
+
+    #[tokio::test]
+    async fn handler_autodiscovers_feeds_of_pages() {
+        let html = |body: &'static str| {
+            warp::reply::with_header(body, http::header::CONTENT_TYPE, "text/html").into_response()
+        };
+        let route = warp::path::full().map(move |path: FullPath| match path.as_str() {
+            "/" => html(r#"<html><head><link rel="alternate" type="application/rss+xml" href="rss.xml"></head></html>"#),
+            "/loop" => html(r#"<html><head><link rel="alternate" type="application/rss+xml" href="/loop"></head></html>"#),
+            _ => FEED.into_response(),
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+
+        for (page, autodiscover, status) in [
+            ("/", true, StatusCode::OK),
+            ("/", false, StatusCode::UNPROCESSABLE_ENTITY),
+            ("/loop", true, StatusCode::UNPROCESSABLE_ENTITY),
+        ] {
+            let url = format!("http://{}{}", addr, page);
+            let res = warp::test::request()
+                .path(&format!(
+                    "/rss?url={}&delay=1&autodiscover={}",
+                    urlencoding::encode(&url),
+                    autodiscover
+                ))
+                .header("host", "example.com")
+                .reply(&test_route(limits.clone()))
+                .await;
+            assert_eq!(res.status(), status, "{} {}", page, autodiscover);
+            if status == StatusCode::OK {
+                let channel = Channel::read_from(&res.body()[..]).unwrap();
+                assert_eq!(channel.items().len(), 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_400_missing_query() {
+        let res = warp::test::request()
+            .path("/rss")
+            .header("host", "example.com")
+            .reply(&test_route(Limits::default()))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn handler_answers_unchanged_items_with_304() {
+        let (addr, requests) = serve_etag_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            cache_ttl: std::time::Duration::ZERO,
+            ..Limits::default()
+        };
+        let route = test_route(limits);
+        let path = format!(
+            "/rss?url={}&delay=1",
+            urlencoding::encode(&format!("http://{}/rss.xml", addr))
+        );
+
+        let res = warp::test::request()
+            .path(&path)
+            .header("host", "example.com")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers()[http::header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(etag.starts_with("W/\""), "{}", etag);
+
+        let res = warp::test::request()
+            .path(&path)
+            .header("host", "example.com")
+            .header("if-none-match", &etag)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert!(res.body().is_empty());
+        assert_eq!(res.headers()[http::header::ETAG], etag);
+        // The client's ETag is recast's own, so it is not sent upstream.
+        assert!(!requests.lock().unwrap()[1].contains_key(http::header::IF_NONE_MATCH));
+
+        let res = warp::test::request()
+            .path(&path)
+            .header("host", "example.com")
+            .header("if-none-match", "W/\"stale\"")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn handler_answers_if_modified_since() {
+        let (addr, _) = serve_feed();
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let route = test_route(limits);
+        let path = format!(
+            "/rss?url={}&delay=1",
+            urlencoding::encode(&format!("http://{}/rss.xml", addr))
+        );
+        let request = |headers: &[(&str, &str)]| {
+            headers.iter().fold(
+                warp::test::request()
+                    .path(&path)
+                    .header("host", "example.com"),
+                |req, (name, value)| req.header(*name, *value),
+            )
+        };
+
+        let res = request(&[]).reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let modified = res.headers()[http::header::LAST_MODIFIED]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(modified.ends_with(" GMT"), "{}", modified);
+
+        let res = request(&[("if-modified-since", &modified)])
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            res.headers()[http::header::LAST_MODIFIED],
+            modified.as_str()
+        );
+        let res = request(&[("if-modified-since", "Thu, 02 Jan 2020 03:04:05 GMT")])
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // `If-None-Match` takes precedence.
+        let res = request(&[
+            ("if-modified-since", &modified),
+            ("if-none-match", "W/\"stale\""),
+        ])
+        .reply(&route)
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn last_modified_moves_when_items_change() {
+        let cache = FeedCache::new(std::time::Duration::ZERO);
+        let at = |s| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let first = at("2024-01-01T00:00:00Z");
+        assert_eq!(
+            cache.last_modified("/rss", "a", at("2024-01-01T00:00:00.5Z")),
+            first
+        );
+        assert_eq!(
+            cache.last_modified("/rss", "a", at("2024-01-01T01:00:00Z")),
+            first
+        );
+        // A change within the same second still gets a later date.
+        assert_eq!(
+            cache.last_modified("/other", "x", at("2024-01-01T00:00:00Z")),
+            first
+        );
+        assert_eq!(
+            cache.last_modified("/other", "y", at("2024-01-01T00:00:00.9Z")),
+            at("2024-01-01T00:00:01Z")
+        );
+        assert_eq!(
+            cache.last_modified("/rss", "b", at("2024-01-02T00:00:00Z")),
+            at("2024-01-02T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn items_etag_changes_with_visible_items() {
+        let query: Query = (raw_query("1d"), &Limits::default()).try_into().unwrap();
+        let etag_at = |now: &str| {
+            let channel = Channel::read_from(FEED.as_bytes()).unwrap();
+            let now = DateTime::parse_from_rfc3339(now)
+                .unwrap()
+                .with_timezone(&Utc);
+            items_etag(
+                &process_feed(
+                    Feed::Rss(Box::new(channel)),
+                    &query,
+                    "http://recast.example/rss".to_string(),
+                    now,
+                )
+                .0,
+            )
+        };
+
+        // Before and after the only item becomes due on 2020-01-03.
+        assert_eq!(
+            etag_at("2020-01-01T00:00:00Z"),
+            etag_at("2020-01-02T00:00:00Z")
+        );
+        assert_ne!(
+            etag_at("2020-01-02T00:00:00Z"),
+            etag_at("2020-01-04T00:00:00Z")
+        );
+        assert_eq!(
+            etag_at("2020-01-04T00:00:00Z"),
+            etag_at("2020-01-05T00:00:00Z")
+        );
+        assert!(etag_matches("W/\"a\", \"b\"", "W/\"b\""));
+        assert!(etag_matches("*", "W/\"b\""));
+        assert!(!etag_matches("W/\"a\"", "W/\"b\""));
+    }
+}
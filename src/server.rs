@@ -0,0 +1,229 @@
+use std::{convert::Infallible, future::Future, net::SocketAddr};
+
+use http::header;
+use tokio::net::{TcpListener, TcpSocket};
+use warp::{
+    hyper::{
+        server::conn::{AddrIncoming, AddrStream},
+        service::{make_service_fn, service_fn, Service},
+    },
+    reply::Response,
+    Filter, Reply,
+};
+
+use crate::{access, cache, compress, config, debug, fetch, metrics, opml, rss, validate};
+
+/// Logs to stderr as `pretty_env_logger` does, filtered by `RUST_LOG`, with
+/// the lines logged while handling a request prefixed with its ID.
+pub fn init_logging() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let logger = builder.build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(access::Traced(logger))).expect("a logger is already set");
+}
+
+/// Serves recast with the configuration in the environment until SIGTERM or
+/// Ctrl-C.
+pub async fn run() {
+    metrics::init();
+
+    let hello = warp::get()
+        .and(warp::path!("hello" / String))
+        .map(|name| format!("Hello, {}!", name));
+
+    // Liveness probe; does no I/O so it stays up regardless of upstream feeds.
+    let healthz = warp::get()
+        .and(warp::path!("healthz"))
+        .map(|| warp::reply::json(&serde_json::json!({ "status": "ok" })));
+
+    let version = warp::get().and(warp::path!("version")).map(|| {
+        warp::reply::json(&serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "git_sha": option_env!("RECAST_GIT_SHA"),
+        }))
+    });
+
+    let metrics = warp::get().and(warp::path!("metrics")).map(|| {
+        warp::reply::with_header(
+            metrics::render(),
+            header::CONTENT_TYPE,
+            prometheus::TEXT_FORMAT,
+        )
+    });
+
+    let config = config::Config::from_env();
+    let tls = config.tls().unwrap_or_else(|e| {
+        log::error!("invalid TLS configuration: {}", e);
+        std::process::exit(1);
+    });
+    let limits = config.limits.clone();
+    let client = fetch::build_client(&limits).expect("failed to build HTTP client");
+    let cache = cache::FeedCache::new(limits.cache_ttl);
+
+    let validate = validate::route(limits.clone(), client.clone(), cache.clone());
+    let debug = debug::route(limits.clone(), client.clone(), cache.clone());
+    let rss = rss::route(limits.clone(), client.clone(), cache.clone())
+        .or(rss::post_route(
+            limits.clone(),
+            client.clone(),
+            cache.clone(),
+        ))
+        .or(opml::route(limits, client, cache));
+    let rss = compress::gzipped(rss);
+    let rss = if config.cors_origins.is_empty() {
+        rss.map(Reply::into_response).boxed()
+    } else {
+        let cors = warp::cors()
+            .allow_methods(["GET", "POST"])
+            .allow_header(header::CONTENT_TYPE)
+            .allow_origins(config.cors_origins.iter().map(String::as_str));
+        rss.with(cors).map(Reply::into_response).boxed()
+    };
+
+    let routes = hello
+        .or(healthz)
+        .or(version)
+        .or(metrics)
+        .or(validate)
+        .or(debug)
+        .or(rss);
+    let routes = access::logged(routes, config.access_log_format);
+
+    match tls {
+        Some((cert, key)) => {
+            let (addr, server) = warp::serve(routes)
+                .tls()
+                .cert_path(cert)
+                .key_path(key)
+                .bind_with_graceful_shutdown(config.bind_addr, shutdown_signal());
+            log::info!("listening on {} over HTTPS", addr);
+            server.await;
+        }
+        None => {
+            let listener = listen(&config).unwrap_or_else(|e| {
+                log::error!("failed to listen on {}: {}", config.bind_addr, e);
+                std::process::exit(1);
+            });
+            let addr = listener.local_addr().unwrap_or(config.bind_addr);
+            log::info!("listening on {} over plain HTTP", addr);
+            if let Err(e) = serve(listener, routes, shutdown_signal()).await {
+                log::error!("server error: {}", e);
+            }
+        }
+    }
+}
+
+/// The address of the client a request came from, put in the request's
+/// extensions by `serve`. warp's `addr::remote` only knows the clients of
+/// connections warp accepted itself.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PeerAddr(pub(crate) SocketAddr);
+
+/// Serves `routes` on `listener` until `signal` completes. Connections get
+/// `TCP_NODELAY`, as warp sets it, and an error accepting one, such as
+/// running out of file descriptors, is retried after a moment rather than
+/// stopping the server.
+async fn serve<F>(
+    listener: TcpListener,
+    routes: F,
+    signal: impl Future<Output = ()>,
+) -> Result<(), warp::hyper::Error>
+where
+    F: Filter<Extract = (Response,), Error = Infallible> + Clone + Send + Sync + 'static,
+    F::Future: Send,
+{
+    let mut incoming = AddrIncoming::from_listener(listener)?;
+    incoming.set_nodelay(true);
+    incoming.set_sleep_on_errors(true);
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let peer = PeerAddr(conn.remote_addr());
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req| {
+                req.extensions_mut().insert(peer);
+                service.clone().call(req)
+            }))
+        }
+    });
+    warp::hyper::Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(signal)
+        .await
+}
+
+/// Binds `config.bind_addr` with the configured `SO_REUSEADDR` and accept
+/// backlog, which `warp::serve(..).bind` leaves at their defaults. Only
+/// plain HTTP listens this way, since warp binds TLS listeners itself.
+fn listen(config: &config::Config) -> std::io::Result<TcpListener> {
+    let socket = if config.bind_addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(config.reuse_addr)?;
+    socket.bind(config.bind_addr)?;
+    socket.listen(config.listen_backlog)
+}
+
+/// Completes on SIGTERM or Ctrl-C, after which the server stops accepting
+/// connections and finishes the requests in flight.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    log::info!("shutting down, waiting for requests in flight");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ratelimit, transform::Limits};
+
+    #[tokio::test]
+    async fn serve_rate_limits_by_peer_address() {
+        let config = config::Config {
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            ..config::Config::default()
+        };
+        let limits = Limits {
+            rate_limit: 1,
+            ..Limits::default()
+        };
+        let routes = access::logged(
+            ratelimit::limit(&limits)
+                .map(warp::reply)
+                .recover(rss::handle_error),
+            access::Format::Text,
+        );
+        let listener = listen(&config).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, routes, std::future::pending()));
+
+        let url = format!("http://{}/", addr);
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(res.status(), 429);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+    }
+}
@@ -0,0 +1,255 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use http::HeaderMap;
+use quick_xml::{events::Event, Reader};
+use rss::{
+    extension::{dublincore, itunes},
+    Channel, Item,
+};
+
+use crate::transform::{
+    dedup_key, item_date, newest_pub_date, process_feed, select_items, to_utf8, Drops, Feed, Query,
+};
+
+/// The size from which `process_channel` streams an RSS feed's items rather
+/// than parsing the feed whole.
+pub(crate) const STREAM_THRESHOLD: usize = 1024 * 1024;
+
+/// The namespace `rss` declares for items with `content:encoded`.
+const CONTENT_NAMESPACE: &str = "http://purl.org/rss/1.0/modules/content/";
+
+/// An item `stream_channel` shows, already serialized.
+struct Fragment {
+    date: Option<DateTime<FixedOffset>>,
+    key: Option<String>,
+    xml: String,
+}
+
+/// Recasts the RSS channel in `content` like `process_feed` followed by
+/// `render` as RSS, but parses its items one at a time and keeps only those
+/// it shows, serialized, so that the parsed feed is never in memory whole.
+///
+/// Returns `None` if `content` is not a well-formed RSS 2.0 channel, for the
+/// caller to parse it whole and report why.
+pub(crate) fn stream_channel(
+    content: &[u8],
+    query: &Query,
+    link: String,
+    now: DateTime<Utc>,
+) -> Option<(String, Drops)> {
+    let content = &to_utf8(content, &HeaderMap::new())[..];
+    let spans = item_spans(content)?;
+
+    let mut header =
+        Vec::with_capacity(content.len() - spans.iter().map(|s| s.len()).sum::<usize>());
+    let mut rest = 0;
+    for span in &spans {
+        header.extend_from_slice(&content[rest..span.start]);
+        rest = span.end;
+    }
+    header.extend_from_slice(&content[rest..]);
+    let mut channel = Channel::read_from(&header[..]).ok()?;
+    if !channel.items().is_empty() {
+        return None;
+    }
+
+    // Namespaces `Channel::to_string` would declare for the items shown.
+    let mut used = BTreeMap::new();
+    let mut failed = false;
+    let namespaces = channel.namespaces.clone();
+    let items = spans.iter().map_while(|span| {
+        let item = parse_item(&content[span.clone()], &namespaces);
+        failed |= item.is_err();
+        item.ok()
+    });
+    let (fragments, drops) = select_items(
+        items,
+        query,
+        now,
+        |_, item| {
+            if item.content().is_some() {
+                used.insert("content".to_string(), CONTENT_NAMESPACE.to_string());
+            }
+            if item.itunes_ext().is_some() {
+                used.insert("itunes".to_string(), itunes::NAMESPACE.to_string());
+            }
+            if item.dublin_core_ext().is_some() {
+                used.insert("dc".to_string(), dublincore::NAMESPACE.to_string());
+            }
+            Fragment {
+                date: item_date(&item).map(|(d, _)| d),
+                key: dedup_key(&item).map(str::to_string),
+                xml: item_xml(item),
+            }
+        },
+        |f| f.date,
+        |f| f.key.as_deref(),
+    );
+    if failed {
+        return None;
+    }
+
+    for (prefix, uri) in used {
+        channel.namespaces.entry(prefix).or_insert(uri);
+    }
+    let channel = match process_feed(Feed::Rss(Box::new(channel)), query, link, now).0 {
+        Feed::Rss(mut channel) => {
+            if let Some(pub_date) = newest_pub_date(fragments.iter().filter_map(|f| f.date), now) {
+                channel.set_pub_date(pub_date);
+            }
+            channel
+        }
+        Feed::Atom(_) => unreachable!("an RSS channel is recast as one"),
+    };
+
+    // `Channel::to_string` writes the items last, right before
+    // `</channel>`.
+    let mut out = channel.to_string();
+    let end = out.rfind("</channel>")?;
+    let items: String = fragments.into_iter().map(|f| f.xml).collect();
+    out.insert_str(end, &items);
+    Some((out, drops))
+}
+
+/// The byte ranges of the `<item>`s of the channel in `content`, or `None` if
+/// it is not an RSS 2.0 document or not well-formed.
+fn item_spans(content: &[u8]) -> Option<Vec<Range<usize>>> {
+    let mut reader = Reader::from_reader(content);
+    let mut buf = Vec::new();
+    let mut skip_buf = Vec::new();
+    let mut depth: usize = 0;
+    let mut in_channel = false;
+    let mut spans = Vec::new();
+    loop {
+        let start = reader.buffer_position();
+        match reader.read_event(&mut buf).ok()? {
+            Event::Start(e) => {
+                match (depth, e.name()) {
+                    (0, b"rss") => {}
+                    (0, _) => return None,
+                    (1, b"channel") => in_channel = true,
+                    (2, b"item") if in_channel => {
+                        reader.read_to_end(b"item", &mut skip_buf).ok()?;
+                        spans.push(start..reader.buffer_position());
+                        buf.clear();
+                        continue;
+                    }
+                    _ => {}
+                }
+                depth += 1;
+            }
+            Event::Empty(e) if depth == 2 && in_channel && e.name() == b"item" => {
+                spans.push(start..reader.buffer_position());
+            }
+            Event::End(_) => {
+                depth = depth.checked_sub(1)?;
+                if depth == 1 {
+                    in_channel = false;
+                }
+            }
+            Event::Eof if depth == 0 => return Some(spans),
+            Event::Eof => return None,
+            _ => {}
+        }
+        buf.clear();
+    }
+}
+
+/// Parses a lone `<item>` element of a channel that declares `namespaces`.
+fn parse_item(xml: &[u8], namespaces: &BTreeMap<String, String>) -> Result<Item, rss::Error> {
+    let mut reader = Reader::from_reader(xml);
+    reader.trim_text(true).expand_empty_elements(true);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(e) => return Item::from_xml(namespaces, &mut reader, e.attributes()),
+            Event::Eof => return Err(rss::Error::Eof),
+            _ => {}
+        }
+    }
+}
+
+/// `item` serialized as `Channel::to_string` writes it.
+fn item_xml(item: Item) -> String {
+    let channel = Channel {
+        items: vec![item],
+        ..Default::default()
+    };
+    let xml = channel.to_string();
+    match (xml.find("<item>"), xml.rfind("</item>")) {
+        (Some(start), Some(end)) => xml[start..end + "</item>".len()].to_string(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::{
+        parse_feed, render,
+        tests::{raw_query, FEED},
+        Format, Limits, RawQuery,
+    };
+
+    const CHANNEL: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/elements/1.1/"><channel><title>Example</title><link>http://example.com</link><description>d</description>
+<atom:link href="https://example.com/rss.xml" rel="self" type="application/rss+xml"/>
+<item><title>Old</title><guid>a</guid><pubDate>Thu, 02 Jan 2020 03:04:05 +0000</pubDate><description>hi</description></item>
+<item><title>Again</title><guid>a</guid><pubDate>Fri, 03 Jan 2020 03:04:05 +0000</pubDate></item>
+<item><title>Creator</title><link>http://example.com/c</link><dc:creator>Host</dc:creator><dc:date>2020-01-04T03:04:05Z</dc:date></item>
+<item><title>Encoded</title><guid>e</guid><content:encoded><![CDATA[<p>hi</p>]]></content:encoded><pubDate>Sat, 04 Jan 2020 03:04:05 +0000</pubDate></item>
+<item><title>Undated</title><guid>u</guid></item>
+<item/>
+<item><title>Soon</title><guid>s</guid><pubDate>Thu, 09 Jan 2020 03:04:05 +0000</pubDate></item>
+<item><title>Later</title><guid>l</guid><pubDate>Fri, 10 Jan 2020 03:04:05 +0000</pubDate></item>
+</channel></rss>"#;
+
+    #[test]
+    fn stream_channel_matches_whole_feed() {
+        let now = DateTime::parse_from_rfc3339("2020-01-10T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let queries = [
+            "",
+            "min_items=6&sort=asc",
+            "min_items=1&limit=2&dedup=true&rewrite_self=true",
+            "keep_undated=true&author=strip&annotate=description",
+            "include=old,encoded&exclude_category=news&title=Rerun",
+        ];
+        for extra in queries {
+            let raw: RawQuery = format!("url=https://example.com/rss.xml&delay=3d&{}", extra)
+                .parse()
+                .unwrap();
+            let query: Query = (raw, &Limits::default()).try_into().unwrap();
+            let link = "https://recast.example/rss".to_string();
+
+            let (body, drops) =
+                stream_channel(CHANNEL.as_bytes(), &query, link.clone(), now).unwrap();
+
+            let feed = parse_feed(CHANNEL.as_bytes(), &HeaderMap::new()).unwrap();
+            let (feed, whole_drops) = process_feed(feed, &query, link, now);
+            assert_eq!(body, render(feed, Format::Rss, now), "{}", extra);
+            assert_eq!(drops, whole_drops, "{}", extra);
+        }
+    }
+
+    #[test]
+    fn stream_channel_leaves_other_documents_to_the_parsers() {
+        let query: Query = (raw_query("1"), &Limits::default()).try_into().unwrap();
+        let now = Utc::now();
+        for content in [
+            r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>T</title></feed>"#,
+            r#"<rss version="2.0"><channel><title>T</title><item><title>Open</item></channel></rss>"#,
+            r#"<rss version="2.0"><channel><title>T</title>"#,
+            r#"<rss version="2.0"><item><title>RSS 0.9</title></item><channel><title>T</title></channel></rss>"#,
+        ] {
+            assert!(
+                stream_channel(content.as_bytes(), &query, String::new(), now).is_none(),
+                "{}",
+                content
+            );
+        }
+        assert!(stream_channel(FEED.as_bytes(), &query, String::new(), now).is_some());
+    }
+}
@@ -0,0 +1,189 @@
+use chrono::DateTime;
+use http::HeaderMap;
+use log::warn;
+use reqwest::Client;
+use serde::Serialize;
+use url::form_urlencoded;
+use warp::{Filter, Rejection, Reply};
+
+use crate::{
+    access,
+    cache::FeedCache,
+    ratelimit,
+    rss::{handle_error, load_feed, with_request_timeout},
+    transform::{item_date, parse_feed_url, Error, Feed, Limits},
+};
+
+/// What `/validate` reports about a feed.
+#[derive(Serialize, Debug)]
+struct Summary {
+    format: &'static str,
+    title: String,
+    item_count: usize,
+    /// How many items have a date recast can read.
+    dated_count: usize,
+    items: Vec<ItemSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    earliest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latest: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ItemSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    /// The item's date, if recast can read it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date: Option<String>,
+}
+
+impl From<&Feed> for Summary {
+    fn from(feed: &Feed) -> Summary {
+        let (format, title, items) = match feed {
+            Feed::Rss(channel) => (
+                "rss",
+                channel.title().to_string(),
+                channel
+                    .items()
+                    .iter()
+                    .map(|i| (i.title().map(str::to_string), item_date(i).map(|(d, _)| d)))
+                    .collect::<Vec<_>>(),
+            ),
+            Feed::Atom(feed) => (
+                "atom",
+                feed.title.value.clone(),
+                feed.entries
+                    .iter()
+                    .map(|e| {
+                        (
+                            Some(e.title.value.clone()),
+                            Some(e.published.unwrap_or(e.updated)),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        let dates: Vec<_> = items.iter().filter_map(|(_, d)| *d).collect();
+        Summary {
+            format,
+            title,
+            item_count: items.len(),
+            dated_count: dates.len(),
+            earliest: dates.iter().min().map(DateTime::to_rfc3339),
+            latest: dates.iter().max().map(DateTime::to_rfc3339),
+            items: items
+                .into_iter()
+                .map(|(title, date)| ItemSummary {
+                    title,
+                    date: date.map(|d| d.to_rfc3339()),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The `/validate` route, which fetches and parses the feed at `url` and
+/// summarizes it, without delaying anything.
+pub(crate) fn route(
+    limits: Limits,
+    client: Client,
+    cache: FeedCache,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("validate"))
+        .and(ratelimit::limit(&limits))
+        .and(access::request_id())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::headers_cloned())
+        .and(warp::any().map(move || limits.clone()))
+        .and(warp::any().map(move || client.clone()))
+        .and(warp::any().map(move || cache.clone()))
+        .and_then(handler)
+        .recover(handle_error)
+}
+
+async fn handler(
+    id: String,
+    r_query: String,
+    headers: HeaderMap,
+    limits: Limits,
+    client: Client,
+    cache: FeedCache,
+) -> Result<impl Reply, Rejection> {
+    let budget = limits.request_timeout;
+    access::traced(
+        id,
+        with_request_timeout(budget, async move {
+            let url = parse_url(&r_query, limits.allow_file).map_err(|e| {
+                warn!("failed to parse query: {}", e);
+                warp::reject::custom(Error::QueryParse(e))
+            })?;
+            let user_agent = headers
+                .get(http::header::USER_AGENT)
+                .and_then(|ua| ua.to_str().ok());
+            let (_, feed) = load_feed(&client, &cache, &url, user_agent, &limits)
+                .await
+                .map_err(warp::reject::custom)?;
+            Ok(warp::reply::json(&Summary::from(&feed)))
+        }),
+    )
+    .await
+}
+
+/// The single `url` in `query`.
+fn parse_url(query: &str, allow_file: bool) -> Result<String, String> {
+    let urls: Vec<_> = form_urlencoded::parse(query.as_bytes())
+        .filter(|(k, _)| k == "url")
+        .map(|(_, v)| v.into_owned())
+        .collect();
+    match &urls[..] {
+        [url] => parse_feed_url(url, allow_file),
+        [] => Err("url is required".to_string()),
+        _ => Err("only one url can be validated at a time".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use super::*;
+    use crate::fetch::build_client;
+
+    const FEED: &str = r#"<rss version="2.0"><channel><title>Example</title><link>http://example.com</link><description>d</description>
+<item><title>New</title><pubDate>Tue, 02 Jan 2024 00:00:00 +0000</pubDate></item>
+<item><title>Undated</title></item>
+<item><title>Old</title><pubDate>Mon, 01 Jan 2024 00:00:00 +0000</pubDate></item>
+</channel></rss>"#;
+
+    #[tokio::test]
+    async fn validate_summarizes_feed() {
+        let (addr, server) =
+            warp::serve(warp::any().map(|| FEED)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let limits = Limits {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Limits::default()
+        };
+        let client = build_client(&limits).unwrap();
+        let cache = FeedCache::new(limits.cache_ttl);
+
+        let res = warp::test::request()
+            .path(&format!(
+                "/validate?url={}",
+                urlencoding::encode(&format!("http://{}/rss.xml", addr))
+            ))
+            .reply(&route(limits, client, cache))
+            .await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["format"], "rss");
+        assert_eq!(body["item_count"], 3);
+        assert_eq!(body["dated_count"], 2);
+        assert_eq!(body["earliest"], "2024-01-01T00:00:00+00:00");
+        assert_eq!(body["latest"], "2024-01-02T00:00:00+00:00");
+        assert_eq!(body["items"][1], serde_json::json!({ "title": "Undated" }));
+    }
+}
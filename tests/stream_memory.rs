@@ -0,0 +1,83 @@
+//! Compares the peak memory of recasting a long RSS feed streamed and whole.
+//! It counts every allocation, so it is a test target of its own, where no
+//! other test runs under its allocator. For numbers worth comparing, run it
+//! in release mode: `cargo test --release --test stream_memory -- --nocapture`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use recast::{process_channel, Limits, Query, RawQuery};
+
+/// Tracks the peak of memory allocated.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let now = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(now, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// The memory allocated at the peak of `f`, beyond what was before it.
+fn peak<T>(f: impl FnOnce() -> T) -> (usize, T) {
+    let base = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    let out = f();
+    (PEAK.load(Ordering::Relaxed) - base, out)
+}
+
+fn query(extra: &str) -> Query {
+    let raw: RawQuery = format!("url=https://example.com/rss.xml&delay=1&limit=10{}", extra)
+        .parse()
+        .unwrap();
+    (raw, &Limits::default()).try_into().unwrap()
+}
+
+#[test]
+fn stream_channel_memory() {
+    let items: String = (0..20_000)
+        .map(|n| {
+            format!(
+                "<item><title>Episode {n}</title><guid>{n}</guid><pubDate>{}</pubDate><description>{}</description></item>\n",
+                (DateTime::parse_from_rfc3339("2020-01-01T00:00:00Z").unwrap()
+                    + Duration::hours(n))
+                .to_rfc2822(),
+                "Lorem ipsum dolor sit amet. ".repeat(20)
+            )
+        })
+        .collect();
+    let feed = format!(
+        "<rss version=\"2.0\"><channel><title>T</title><link>http://example.com</link><description>d</description>{}</channel></rss>",
+        items
+    );
+    let now = Utc::now();
+
+    let (streamed, out) = peak(|| process_channel(feed.as_bytes(), &query(""), String::new(), now));
+    // Rendered as Atom, the feed is parsed whole.
+    let (whole, _) =
+        peak(|| process_channel(feed.as_bytes(), &query("&format=atom"), String::new(), now));
+    assert!(out.unwrap().contains("<title>Episode 19999</title>"));
+    println!(
+        "{} byte feed: {} bytes at peak streamed, {} bytes whole",
+        feed.len(),
+        streamed,
+        whole
+    );
+    assert!(streamed < whole);
+    assert!(streamed < 2 * feed.len());
+}